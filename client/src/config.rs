@@ -4,9 +4,11 @@ use dotenvy::dotenv;
 use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::OnceLock;
 use std::{
     env,
     io::{self, Write},
+    time::Duration,
 };
 use tracing::{error, info};
 use url::Url;
@@ -17,9 +19,140 @@ pub struct AppConfig {
     pub client_id: String,
     pub secret_token: String,
     pub target_http_service_url: String,
+    #[serde(default)]
+    pub upstream_protocol: UpstreamProtocol,
     pub allowed_paths: Vec<String>,
     pub allowed_ips: Vec<String>,
     pub allowed_asns: Vec<u32>,
+    #[serde(default)]
+    pub target_tcp_address: Option<String>,
+    #[serde(default)]
+    pub target_udp_address: Option<String>,
+    #[serde(default)]
+    pub load_balancing: Option<LoadBalancing>,
+    /// Host names that point at the tunnel server and are routed to this tunnel.
+    #[serde(default)]
+    pub custom_domains: Vec<String>,
+}
+
+/// The HTTP version the client speaks to the local service.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 over plain TCP, with prior knowledge.
+    H2c,
+    /// HTTP/2 over TLS.
+    H2,
+}
+
+impl UpstreamProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamProtocol::Http1 => "http1",
+            UpstreamProtocol::H2c => "h2c",
+            UpstreamProtocol::H2 => "h2",
+        }
+    }
+}
+
+/// Lets several clients serve the same Client ID, with the server spreading the requests
+/// over them. All of them must use the same strategy and allow lists.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoadBalancing {
+    pub strategy: BalancingStrategy,
+    /// Only used by the weighted strategy.
+    pub weight: u32,
+    /// Keeps each visitor on the same client: `cookie`, `ip` or `header:<name>`.
+    #[serde(default)]
+    pub affinity: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancingStrategy {
+    RoundRobin,
+    LeastInFlight,
+    Weighted,
+}
+
+impl BalancingStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BalancingStrategy::RoundRobin => "round_robin",
+            BalancingStrategy::LeastInFlight => "least_in_flight",
+            BalancingStrategy::Weighted => "weighted",
+        }
+    }
+}
+
+/// How long the local service may take to send its response headers
+/// (`RESPONSE_HEAD_TIMEOUT_SECS`, default 30). It is the server's setting of the same name, so
+/// raising it on the server should go together with raising it here.
+pub fn response_head_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        dotenv().ok();
+        env_secs("RESPONSE_HEAD_TIMEOUT_SECS", 30)
+    })
+}
+
+/// How the client watches its connection to the server. These come from the environment
+/// rather than the saved configurations, since they depend on the network, not the tunnel.
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig {
+    /// How often the server is pinged (`PING_INTERVAL_SECS`, default 20).
+    pub ping_interval: Duration,
+    /// How long to wait for anything from the server after a ping before giving up on the
+    /// connection (`PONG_TIMEOUT_SECS`, default 10).
+    pub pong_timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        Self {
+            ping_interval: env_secs("PING_INTERVAL_SECS", 20),
+            pong_timeout: env_secs("PONG_TIMEOUT_SECS", 10),
+        }
+    }
+}
+
+/// How the client gets back online after losing the connection to the server. Like the
+/// heartbeat settings, these come from the environment.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectConfig {
+    /// Attempts before giving up (`RECONNECT_MAX_ATTEMPTS`, default 10, `0` for no limit).
+    pub max_attempts: u32,
+    /// Delay before the first attempt, doubled for each further one
+    /// (`RECONNECT_BASE_DELAY_SECS`, default 1).
+    pub base_delay: Duration,
+    /// Upper bound for the delay between attempts (`RECONNECT_MAX_DELAY_SECS`, default 60).
+    pub max_delay: Duration,
+}
+
+impl ReconnectConfig {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let max_attempts = env::var("RECONNECT_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|val| val.parse::<u32>().ok())
+            .unwrap_or(10);
+        Self {
+            max_attempts,
+            base_delay: env_secs("RECONNECT_BASE_DELAY_SECS", 1),
+            max_delay: env_secs("RECONNECT_MAX_DELAY_SECS", 60),
+        }
+    }
+}
+
+fn env_secs(name: &str, default: u64) -> Duration {
+    env::var(name)
+        .ok()
+        .and_then(|val| val.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(default))
 }
 
 /// The main entry point for configuration.
@@ -141,18 +274,33 @@ fn gather_new_config() -> AppConfig {
     let secret_token = get_input_with_default("Enter Secret Token", &secret_token_default);
 
     let target_http_service_url = get_target_local_url();
+    let upstream_protocol = get_upstream_protocol(&target_http_service_url);
     let allowed_paths = get_allowed_paths();
     let allowed_ips = get_allowed_ips();
     let allowed_asns = get_allowed_asns();
+    let target_tcp_address = get_target_socket_address("TCP", "localhost:5432 or localhost:22");
+    let target_udp_address = get_target_socket_address("UDP", "localhost:53 or localhost:51820");
+    // TCP and UDP tunnels have a single public port per Client ID, so they cannot be shared.
+    let load_balancing = if target_tcp_address.is_none() && target_udp_address.is_none() {
+        get_load_balancing()
+    } else {
+        None
+    };
+    let custom_domains = get_custom_domains();
 
     AppConfig {
         server_ws_url,
         client_id,
         secret_token,
         target_http_service_url,
+        upstream_protocol,
         allowed_paths,
         allowed_ips,
         allowed_asns,
+        target_tcp_address,
+        target_udp_address,
+        load_balancing,
+        custom_domains,
     }
 }
 
@@ -331,6 +479,45 @@ fn get_allowed_paths() -> Vec<String> {
     paths
 }
 
+fn get_custom_domains() -> Vec<String> {
+    println!("\n▶ Optionally route your own domains to the tunnel (e.g., app.example.com).");
+    println!(
+        "  - Their DNS must point at the tunnel server. Press Enter on an empty line to finish."
+    );
+
+    let mut domains = Vec::new();
+    loop {
+        print!("> ");
+        io::Write::flush(&mut io::stdout()).expect("Failed to flush stdout");
+
+        let mut domain = String::new();
+        match io::stdin().read_line(&mut domain) {
+            Ok(0) => break, // EOF
+            Ok(_) => {
+                let domain = domain.trim().to_ascii_lowercase();
+                if domain.is_empty() {
+                    break;
+                }
+
+                match Url::parse(&format!("http://{}", domain)) {
+                    Ok(url) if url.host_str() == Some(domain.as_str()) && domain.contains('.') => {
+                        if !domains.contains(&domain) {
+                            println!("  ✅ Added domain: '{}'", domain);
+                            domains.push(domain);
+                        }
+                    }
+                    _ => eprintln!("  ❌ Error: Invalid domain name. Please try again."),
+                }
+            }
+            Err(_) => {
+                eprintln!("Error: Failed to read input.");
+                break;
+            }
+        }
+    }
+    domains
+}
+
 fn get_target_local_url() -> String {
     let target_http_service_url_default =
         env::var("TARGET_HTTP_SERVICE_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
@@ -355,6 +542,148 @@ fn get_target_local_url() -> String {
     }
 }
 
+fn get_upstream_protocol(target_http_service_url: &str) -> UpstreamProtocol {
+    let scheme = Url::parse(target_http_service_url)
+        .map(|url| url.scheme().to_string())
+        .unwrap_or_default();
+    loop {
+        let protocol = get_input_with_default(
+            "Protocol for the local service (http1, h2c or h2)",
+            UpstreamProtocol::Http1.as_str(),
+        );
+        match protocol.as_str() {
+            "http1" => return UpstreamProtocol::Http1,
+            "h2c" if scheme == "http" => return UpstreamProtocol::H2c,
+            "h2" if scheme == "https" => return UpstreamProtocol::H2,
+            "h2c" => eprintln!("Error: h2c needs an http:// URL. Please try again."),
+            "h2" => eprintln!("Error: h2 needs an https:// URL. Please try again."),
+            _ => eprintln!("Error: Unknown protocol. Please try again."),
+        }
+    }
+}
+
+fn get_target_socket_address(transport: &str, examples: &str) -> Option<String> {
+    println!(
+        "\n▶ Optionally expose a local {} service as well (e.g., {}).",
+        transport, examples
+    );
+    println!("  - The server will assign a public port for it. Press Enter to skip.");
+
+    loop {
+        print!("> ");
+        io::Write::flush(&mut io::stdout()).expect("Failed to flush stdout");
+
+        let mut address = String::new();
+        match io::stdin().read_line(&mut address) {
+            Ok(0) => return None, // EOF
+            Ok(_) => {
+                let address = address.trim().to_string();
+                if address.is_empty() {
+                    return None;
+                }
+
+                match address.rsplit_once(':') {
+                    Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                        println!("  ✅ {} tunnel will forward to '{}'.", transport, address);
+                        return Some(address);
+                    }
+                    _ => eprintln!("  ❌ Error: Expected host:port. Please try again."),
+                }
+            }
+            Err(_) => {
+                eprintln!("Error: Failed to read input.");
+                return None;
+            }
+        }
+    }
+}
+
+fn get_load_balancing() -> Option<LoadBalancing> {
+    println!(
+        "\n▶ Optionally share this Client ID with other clients to spread requests over them."
+    );
+    println!("  - Strategies: round_robin, least_in_flight or weighted. Press Enter to skip.");
+
+    loop {
+        print!("> ");
+        io::Write::flush(&mut io::stdout()).expect("Failed to flush stdout");
+
+        let mut strategy = String::new();
+        match io::stdin().read_line(&mut strategy) {
+            Ok(0) => return None, // EOF
+            Ok(_) => {
+                let strategy = match strategy.trim() {
+                    "" => return None,
+                    "round_robin" => BalancingStrategy::RoundRobin,
+                    "least_in_flight" => BalancingStrategy::LeastInFlight,
+                    "weighted" => BalancingStrategy::Weighted,
+                    _ => {
+                        eprintln!("  ❌ Error: Unknown strategy. Please try again.");
+                        continue;
+                    }
+                };
+                let weight = if strategy == BalancingStrategy::Weighted {
+                    loop {
+                        match get_input_with_default("  Weight of this client", "1").parse() {
+                            Ok(weight) if weight >= 1 => break weight,
+                            _ => eprintln!("  ❌ Error: Expected a positive number."),
+                        }
+                    }
+                } else {
+                    1
+                };
+                println!("  ✅ Requests will be balanced {}.", strategy.as_str());
+                let affinity = get_affinity();
+                return Some(LoadBalancing {
+                    strategy,
+                    weight,
+                    affinity,
+                });
+            }
+            Err(_) => {
+                eprintln!("Error: Failed to read input.");
+                return None;
+            }
+        }
+    }
+}
+
+fn get_affinity() -> Option<String> {
+    println!(
+        "\n▶ Optionally keep each visitor on the same client, for apps that keep session state."
+    );
+    println!("  - Use 'cookie', 'ip' or 'header:<name>' (e.g., header:X-Session-Id). Press Enter to skip.");
+
+    loop {
+        print!("> ");
+        io::Write::flush(&mut io::stdout()).expect("Failed to flush stdout");
+
+        let mut affinity = String::new();
+        match io::stdin().read_line(&mut affinity) {
+            Ok(0) => return None, // EOF
+            Ok(_) => {
+                let affinity = affinity.trim();
+                let valid = match affinity.split_once(':') {
+                    None => affinity.is_empty() || affinity == "cookie" || affinity == "ip",
+                    Some((kind, name)) => kind == "header" && !name.is_empty(),
+                };
+                if !valid {
+                    eprintln!("  ❌ Error: Unknown affinity. Please try again.");
+                } else if affinity.is_empty() {
+                    return None;
+                } else {
+                    println!("  ✅ Visitors will be kept on one client by {}.", affinity);
+                    return Some(affinity.to_string());
+                }
+            }
+            Err(_) => {
+                eprintln!("Error: Failed to read input.");
+                return None;
+            }
+        }
+    }
+}
+
 fn get_allowed_asns() -> Vec<u32> {
     println!("\n▶ Enter allowed ASNs for the tunnel (e.g., AS15169).");
     println!("  - Press Enter on an empty line to finish. If no ASNs are provided, all ASNs will be allowed.");
@@ -1,135 +1,289 @@
-use crate::models::{TunneledHttpResponse, TunneledRequest};
-use base64::{engine::general_purpose, Engine};
-use reqwest::{Client, Method as ReqwestMethod};
+use crate::config::response_head_timeout;
+use crate::outbound::FrameSender;
+use crate::protocol::{
+    self, BufferedResponse, Capabilities, Capability, ErrorCode, Frame, HeaderBytes, Headers,
+    RequestHead, ResponseHead, TunnelError, MAX_CHUNK_SIZE,
+};
+use http_body_util::BodyExt;
+use reqwest::{Body, Client, Method as ReqwestMethod, Response};
 use tracing::{error, info, warn};
-use tungstenite::http::HeaderValue;
+use tungstenite::http::{HeaderMap, HeaderName, HeaderValue};
 
-pub async fn forward_request_to_local_service(
+/// Hop-by-hop headers of the visitor's request that must not be passed on to the local service.
+/// `Trailer` is kept, since HTTP/1.1 can only send the request trailers it announces, and so is
+/// `TE: trailers`, which gRPC servers require.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Builds a plain-text response for errors that happen before the local service answers.
+pub fn error_response(id: String, status: u16, message: &str) -> BufferedResponse {
+    let head = ResponseHead {
+        id,
+        status,
+        headers: Vec::new(),
+    };
+    (head, message.as_bytes().to_vec())
+}
+
+/// Sends a request to the local service and returns its response as soon as the headers arrive.
+/// Failures are turned into an error response for the visitor.
+async fn send_to_local_service(
     http_client: &Client,
-    tunneled_req: TunneledRequest,
+    head: RequestHead,
+    body: Option<Body>,
     target_http_service_url: &str,
-) -> TunneledHttpResponse {
-    let local_service_url = format!("{}{}", target_http_service_url, tunneled_req.path);
+) -> Result<Response, BufferedResponse> {
+    // The query is appended as is so that signed URLs keep matching their signature.
+    let local_service_url = match &head.query {
+        Some(query) => format!("{}{}?{}", target_http_service_url, head.path, query),
+        None => format!("{}{}", target_http_service_url, head.path),
+    };
     info!(
         "Forwarding request (ID: {}) to local service: {} {}",
-        tunneled_req.id, tunneled_req.method, local_service_url
+        head.id, head.method, local_service_url
     );
 
-    let method = match ReqwestMethod::from_bytes(tunneled_req.method.as_bytes()) {
+    let method = match ReqwestMethod::from_bytes(head.method.as_bytes()) {
         Ok(m) => m,
         Err(_) => {
             error!(
                 "Invalid HTTP method received for ID {}: {}",
-                tunneled_req.id, tunneled_req.method
+                head.id, head.method
             );
-            return TunneledHttpResponse {
-                id: tunneled_req.id,
-                status: 400,
-                headers: std::collections::HashMap::new(),
-                body: Some(general_purpose::STANDARD.encode("Invalid HTTP method")),
-            };
+            return Err(error_response(head.id, 400, "Invalid HTTP method"));
         }
     };
 
     let mut request_builder = http_client.request(method, &local_service_url);
 
-    if !tunneled_req.query_params.is_empty() {
-        request_builder = request_builder.query(&tunneled_req.query_params);
+    let is_grpc = head.headers.iter().any(|(key, value)| {
+        key.eq_ignore_ascii_case("content-type") && value.0.starts_with(b"application/grpc")
+    });
+    let has_grpc_deadline = is_grpc
+        && head
+            .headers
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case("grpc-timeout"));
+    let headers = head
+        .headers
+        .into_iter()
+        .filter(|(key, value)| {
+            let key = key.to_ascii_lowercase();
+            (key == "te" && value.0.eq_ignore_ascii_case(b"trailers"))
+                || !HOP_BY_HOP_HEADERS.contains(&key.as_str())
+        })
+        .collect();
+    let mut header_map = HeaderMap::new();
+    append_headers(&mut header_map, headers, &head.id);
+    // The local service is reached under its own address, so it learns the visitor's
+    // `:authority` and `:scheme` the way it would behind any other reverse proxy.
+    let forwarded = [
+        ("x-forwarded-host", &head.authority),
+        ("x-forwarded-proto", &head.scheme),
+    ];
+    for (name, value) in forwarded {
+        let value = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok());
+        if let Some(value) = value {
+            header_map.entry(name).or_insert(value);
+        }
     }
+    request_builder = request_builder.headers(header_map);
 
-    for (key, value) in tunneled_req.headers {
-        if key.eq_ignore_ascii_case("host")
-            || key.eq_ignore_ascii_case("connection")
-            || key.eq_ignore_ascii_case("keep-alive")
-            || key.eq_ignore_ascii_case("proxy-authenticate")
-            || key.eq_ignore_ascii_case("proxy-authorization")
-            || key.eq_ignore_ascii_case("te")
-            || key.eq_ignore_ascii_case("trailer")
-            || key.eq_ignore_ascii_case("transfer-encoding")
-            || key.eq_ignore_ascii_case("upgrade")
-        {
-            continue;
-        }
-        if let Ok(header_value) = HeaderValue::from_str(&value) {
-            request_builder = request_builder.header(&key, header_value);
-        } else {
-            warn!(
-                "Skipping invalid header value for key '{}' (ID {}): {}",
-                key, tunneled_req.id, value
-            );
-        }
+    if let Some(body) = body {
+        request_builder = request_builder.body(body);
     }
 
-    if let Some(body_str) = tunneled_req.body {
-        if !body_str.is_empty() {
-            match general_purpose::STANDARD.decode(&body_str) {
-                Ok(decoded_body) => {
-                    request_builder = request_builder.body(decoded_body);
-                }
-                Err(e) => {
-                    error!(
-                        "Failed to base64 decode request body for ID {}: {}",
-                        tunneled_req.id, e
-                    );
-                    return TunneledHttpResponse {
-                        id: tunneled_req.id,
-                        status: 400,
-                        headers: std::collections::HashMap::new(),
-                        body: Some(
-                            general_purpose::STANDARD.encode("Failed to decode request body"),
-                        ),
-                    };
-                }
-            }
+    // A gRPC call with a deadline is left to the local service, which enforces its
+    // `grpc-timeout` itself and may send the head of a streaming call late.
+    let resp = if has_grpc_deadline {
+        Ok(request_builder.send().await)
+    } else {
+        tokio::time::timeout(response_head_timeout(), request_builder.send()).await
+    };
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(_) => {
+            error!(
+                "Timed out waiting for the local service to respond for ID {}",
+                head.id
+            );
+            return Err(error_response(head.id, 504, "Gateway Timeout"));
         }
-    }
+    };
 
-    match request_builder.send().await {
+    match resp {
         Ok(resp) => {
             info!(
                 "Received response from local service for ID {}. Status: {}",
-                tunneled_req.id,
+                head.id,
                 resp.status()
             );
-            let status = resp.status().as_u16();
-            let mut headers_map = std::collections::HashMap::new();
-            for (key, value) in resp.headers() {
-                headers_map.insert(
-                    key.to_string(),
-                    value.to_str().unwrap_or_default().to_string(),
-                );
-            }
+            Ok(resp)
+        }
+        Err(e) => {
+            error!(
+                "Failed to send request to local service for ID {}: {:?}",
+                head.id, e
+            );
+            Err(error_response(head.id, 503, "Service Unavailable"))
+        }
+    }
+}
 
-            let body_bytes = match resp.bytes().await {
-                Ok(bytes) => bytes.to_vec(),
-                Err(e) => {
-                    error!(
-                        "Failed to read response body from local service for ID {}: {:?}",
-                        tunneled_req.id, e
-                    );
-                    Vec::new()
-                }
-            };
-            let body_base64 = general_purpose::STANDARD.encode(body_bytes);
+/// Converts headers for the tunnel as raw bytes, keeping every value of repeated fields such
+/// as `Set-Cookie` in the order they were sent.
+pub fn headers_to_list(headers: &HeaderMap) -> Headers {
+    headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), HeaderBytes(v.as_bytes().to_vec())))
+        .collect()
+}
 
-            TunneledHttpResponse {
-                id: tunneled_req.id,
-                status,
-                headers: headers_map,
-                body: Some(body_base64),
+/// Appends tunnelled header fields to `header_map`. Fields that are not valid HTTP are
+/// dropped and counted.
+pub fn append_headers(header_map: &mut HeaderMap, headers: Headers, id: &str) {
+    for (key, value) in headers {
+        match (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_bytes(&value.0),
+        ) {
+            (Ok(name), Ok(value)) => {
+                header_map.append(name, value);
+            }
+            _ => {
+                let dropped = protocol::record_dropped_header();
+                warn!(
+                    "Dropped invalid header '{}' for ID {} ({} dropped so far)",
+                    key, id, dropped
+                );
             }
         }
+    }
+}
+
+fn response_head(id: String, resp: &Response) -> ResponseHead {
+    ResponseHead {
+        id,
+        status: resp.status().as_u16(),
+        headers: headers_to_list(resp.headers()),
+    }
+}
+
+/// Forwards a request with a fully buffered body and buffers the whole response.
+/// Used for servers that only speak the JSON text protocol.
+pub async fn forward_request_to_local_service(
+    http_client: &Client,
+    head: RequestHead,
+    body: Vec<u8>,
+    target_http_service_url: &str,
+) -> BufferedResponse {
+    let id = head.id.clone();
+    let body = (!body.is_empty()).then(|| Body::from(body));
+    let resp = match send_to_local_service(http_client, head, body, target_http_service_url).await {
+        Ok(resp) => resp,
+        Err(error_response) => return error_response,
+    };
+
+    let response_head = response_head(id.clone(), &resp);
+    let body_bytes = match resp.bytes().await {
+        Ok(bytes) => bytes.to_vec(),
         Err(e) => {
             error!(
-                "Failed to send request to local service for ID {}: {:?}",
-                tunneled_req.id, e
+                "Failed to read response body from local service for ID {}: {:?}",
+                id, e
             );
-            TunneledHttpResponse {
-                id: tunneled_req.id,
-                status: 503,
-                headers: std::collections::HashMap::new(),
-                body: Some(general_purpose::STANDARD.encode(format!("Service Unavailable"))),
+            Vec::new()
+        }
+    };
+    (response_head, body_bytes)
+}
+
+/// Forwards a request whose body arrives as it is read from the tunnel, and streams the
+/// response back as a `Response` frame followed by `Data` frames, a `Trailers` frame if the
+/// local service sent trailers, and a closing `End` frame. A body that breaks off ends with an
+/// `Error` frame instead, so the visitor does not take it for complete.
+pub async fn stream_request_to_local_service(
+    http_client: &Client,
+    head: RequestHead,
+    body: Option<Body>,
+    target_http_service_url: &str,
+    tx: FrameSender,
+    capabilities: Capabilities,
+) {
+    let id = head.id.clone();
+    let (response_head, mut body) =
+        match send_to_local_service(http_client, head, body, target_http_service_url).await {
+            Ok(resp) => (response_head(id.clone(), &resp), Body::from(resp)),
+            Err((head, body)) => (head, Body::from(body)),
+        };
+
+    if tx.send_frame(Frame::Response(response_head)).await.is_err() {
+        return;
+    }
+
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!(
+                    "Failed to read response body from local service for ID {}: {:?}",
+                    id, e
+                );
+                if !capabilities.contains(Capability::Errors) {
+                    // Older servers have no way to hear about it, so the stream is left
+                    // unfinished rather than ended as if the body were complete.
+                    return;
+                }
+                let error = TunnelError {
+                    id: Some(id),
+                    code: ErrorCode::UpstreamFailed,
+                    message: format!("The local service's response body broke off: {}", e),
+                };
+                let _ = tx.send_frame(Frame::Error(error)).await;
+                return;
+            }
+        };
+        let chunk = match frame.into_data() {
+            Ok(chunk) => chunk,
+            Err(frame) => {
+                let Ok(trailers) = frame.into_trailers() else {
+                    continue;
+                };
+                if !capabilities.contains(Capability::Trailers) {
+                    warn!(
+                        "Dropped the response trailers for ID {}, the server does not support them",
+                        id
+                    );
+                    continue;
+                }
+                let frame = Frame::Trailers {
+                    id: id.clone(),
+                    headers: headers_to_list(&trailers),
+                };
+                if tx.send_frame(frame).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        for piece in chunk.chunks(MAX_CHUNK_SIZE) {
+            let frame = Frame::Data {
+                id: id.clone(),
+                chunk: piece.to_vec(),
+            };
+            if tx.send_frame(frame).await.is_err() {
+                return;
             }
         }
     }
+
+    let _ = tx.send_frame(Frame::End { id }).await;
 }
@@ -2,20 +2,28 @@ mod config;
 mod config_manager;
 mod http_handler;
 mod models;
+mod outbound;
+mod reconnect;
+mod tcp_tunnel;
+mod udp_tunnel;
 mod utils;
 mod websocket_handler;
+mod websocket_tunnel;
 
-use crate::websocket_handler::handle_websocket_messages;
-use config::AppConfig;
+use crate::outbound::FrameSender;
+use crate::protocol::Capability;
+use crate::websocket_handler::{handle_websocket_messages, send_websocket_messages};
+use config::{AppConfig, HeartbeatConfig, ReconnectConfig, UpstreamProtocol};
 use config_manager::load_configs;
-use futures_util::SinkExt;
 use reqwest::Client;
-use std::time::Duration;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
 use tracing::{error, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use websocket_handler::connect_to_websocket;
+use url::Url;
+use websocket_handler::{connect_to_websocket, SessionInfo, WsReceiver, WsSender};
+use yats_protocol as protocol;
 
 #[tokio::main]
 async fn main() {
@@ -46,64 +54,157 @@ async fn main() {
         }
     };
 
-    let (mut ws_sender, ws_receiver) = match connect_to_websocket(&config).await {
-        Ok((sender, receiver)) => (sender, receiver),
+    let mut connection = match connect_to_websocket(&config, None).await {
+        Ok(connected) => connected,
         Err(e) => {
             error!("Failed to connect: {:?}", e);
             eprintln!(
-                "\nERROR: Could not connect. Please check the server URL, client ID, token, and ensure the server is running."
+                "\nERROR: Could not connect: {}\nPlease check the server URL, client ID, token, and ensure the server is running.",
+                e
             );
             return;
         }
     };
 
-    let (tx, mut rx) = mpsc::channel::<WsMessage>(100);
-
-    let tx_ctrlc = tx.clone();
+    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
     tokio::spawn(async move {
-        if tokio::signal::ctrl_c().await.is_ok() {
-            info!("Ctrl-C received, sending Close frame to server...");
-            let _ = tx_ctrlc.send(WsMessage::Close(None)).await;
-        }
+        wait_for_shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
     });
 
-    // Handle SIGTERM (Unix only)
-    #[cfg(unix)]
-    {
-        use tokio::signal::unix::{signal, SignalKind};
-        let tx_sigterm = tx.clone();
-        tokio::spawn(async move {
-            let mut sigterm =
-                signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
-            sigterm.recv().await;
-            info!("SIGTERM received, sending Close frame to server...");
-            let _ = tx_sigterm.send(WsMessage::Close(None)).await;
-        });
+    // No overall timeout: streamed responses such as SSE stay open as long as the local
+    // service keeps writing. Waiting for the response head is bounded in `http_handler`.
+    let http_client = Client::builder().connect_timeout(Duration::from_secs(10));
+    let http_client = match config.upstream_protocol {
+        UpstreamProtocol::Http1 => http_client.http1_only(),
+        UpstreamProtocol::H2c | UpstreamProtocol::H2 => http_client.http2_prior_knowledge(),
     }
+    .build()
+    .expect("Failed to build request client");
+    let heartbeat_config = HeartbeatConfig::from_env();
+    let reconnect_config = ReconnectConfig::from_env();
 
-    tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            if let Err(e) = ws_sender.send(message).await {
-                error!("Failed to send message over WebSocket: {:?}", e);
+    // The same configuration, and with it the client id and allow lists, is used for every
+    // connection, so visitors find the tunnel where it was once it is back.
+    loop {
+        let (ws_sender, ws_receiver, session) = connection;
+        print_tunnel_status(&config, &session);
+        let resume_token = session.resume_token.clone();
+        let went_away = run_session(
+            ws_sender,
+            ws_receiver,
+            session,
+            &config,
+            &http_client,
+            heartbeat_config,
+            &shutdown_rx,
+        )
+        .await;
+        if *shutdown_rx.borrow() {
+            break;
+        }
+
+        let offline_since = Instant::now();
+        if !went_away {
+            println!("\n⚠️ Lost the connection to the server.");
+        }
+        match reconnect::reconnect(
+            &config,
+            resume_token.as_deref(),
+            &reconnect_config,
+            went_away,
+            &mut shutdown_rx,
+        )
+        .await
+        {
+            Some(connected) => {
+                println!(
+                    "✅ Reconnected after {:.1?} offline.",
+                    offline_since.elapsed()
+                );
+                connection = connected;
+            }
+            None => {
+                if !*shutdown_rx.borrow() {
+                    println!(
+                        "❌ The tunnel is down. It was offline for {:.1?}.",
+                        offline_since.elapsed()
+                    );
+                }
                 break;
             }
         }
-        info!("WebSocket sender task shutting down.");
+    }
+
+    info!("Tunnel Client shutting down.");
+}
+
+/// Serves one connection to the server until it is lost or the client shuts down. Returns
+/// whether the server announced that it was going away.
+async fn run_session(
+    ws_sender: WsSender,
+    ws_receiver: WsReceiver,
+    session: SessionInfo,
+    config: &AppConfig,
+    http_client: &Client,
+    heartbeat_config: HeartbeatConfig,
+    shutdown_rx: &watch::Receiver<bool>,
+) -> bool {
+    let tx = FrameSender::default();
+
+    let tx_shutdown = tx.clone();
+    let mut shutdown = shutdown_rx.clone();
+    let close_on_shutdown = tokio::spawn(async move {
+        if shutdown
+            .wait_for(|shutting_down| *shutting_down)
+            .await
+            .is_ok()
+        {
+            info!("Sending Close frame to server...");
+            let _ = tx_shutdown.send_message(WsMessage::Close(None));
+        }
     });
 
-    print_tunnel_status(&config);
+    let compress = session.capabilities.contains(Capability::Compression);
+    let writer = tokio::spawn(send_websocket_messages(ws_sender, tx.clone(), compress));
 
-    let http_client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .expect("Failed to build request client");
+    let went_away = handle_websocket_messages(
+        ws_receiver,
+        tx.clone(),
+        http_client.clone(),
+        config.clone(),
+        session.capabilities,
+        heartbeat_config,
+    )
+    .await;
 
-    handle_websocket_messages(ws_receiver, tx, http_client, config).await;
+    // Nothing may linger from this connection once the next one is up.
+    close_on_shutdown.abort();
+    tx.close();
+    writer.abort();
+    went_away
+}
 
-    info!("Tunnel Client shutting down.");
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Ctrl-C received."),
+            _ = sigterm.recv() => info!("SIGTERM received."),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Ctrl-C received.");
+    }
 }
 
-fn print_tunnel_status(config: &AppConfig) {
+fn print_tunnel_status(config: &AppConfig, session: &SessionInfo) {
     let client_public_url_base = config
         .server_ws_url
         .replace("ws://", "http://")
@@ -112,15 +213,33 @@ fn print_tunnel_status(config: &AppConfig) {
         .to_string();
 
     println!("\n🚀 Your tunnel is active!");
+    match session.protocol_version {
+        Some(version) => println!(
+            "Protocol version {}, enabled features: {}",
+            version, session.capabilities
+        ),
+        None => println!("⚠️ The server is older and did not report its protocol version."),
+    }
 
     if config.allowed_paths.is_empty() {
         println!("No public paths are configured. No remote requests will be forwarded.");
-        return;
     } else {
         println!("Requests to:");
         for path in &config.allowed_paths {
             println!("  {}/{}{}", client_public_url_base, config.client_id, path);
         }
+        // Subdomains and custom domains reach the local service without the Client ID in the
+        // path.
+        let scheme = if config.server_ws_url.starts_with("wss://") {
+            "https"
+        } else {
+            "http"
+        };
+        for domain in session.tunnel_host.iter().chain(&config.custom_domains) {
+            for path in &config.allowed_paths {
+                println!("  {}://{}{}", scheme, domain, path);
+            }
+        }
     }
 
     if config.allowed_ips.is_empty() {
@@ -141,7 +260,48 @@ fn print_tunnel_status(config: &AppConfig) {
     }
 
     println!(
-        "\nWill be forwarded to your local service at: {}",
-        config.target_http_service_url
+        "\nWill be forwarded to your local service at: {} ({})",
+        config.target_http_service_url,
+        config.upstream_protocol.as_str()
     );
+    if let Some(load_balancing) = &config.load_balancing {
+        println!(
+            "Shared with other clients using this Client ID, balanced {} (weight {}).",
+            load_balancing.strategy.as_str(),
+            load_balancing.weight
+        );
+        if let Some(affinity) = &load_balancing.affinity {
+            println!("Each visitor is kept on the same client by {}.", affinity);
+        }
+    }
+
+    let server_host = Url::parse(&config.server_ws_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    if let Some(target_tcp_address) = &config.target_tcp_address {
+        match session.tcp_port {
+            Some(port) => println!(
+                "TCP connections to {}:{} will be forwarded to: {}",
+                server_host, port, target_tcp_address
+            ),
+            None => println!(
+                "⚠️ The server did not assign a TCP tunnel port. {} is not exposed.",
+                target_tcp_address
+            ),
+        }
+    }
+
+    if let Some(target_udp_address) = &config.target_udp_address {
+        match session.udp_port {
+            Some(port) => println!(
+                "UDP datagrams to {}:{} will be forwarded to: {}",
+                server_host, port, target_udp_address
+            ),
+            None => println!(
+                "⚠️ The server did not assign a UDP tunnel port. {} is not exposed.",
+                target_udp_address
+            ),
+        }
+    }
 }
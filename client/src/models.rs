@@ -1,5 +1,8 @@
+use crate::protocol::{RequestHead, ResponseHead};
+use base64::{engine::general_purpose, Engine};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use url::form_urlencoded;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TunneledRequest {
@@ -18,3 +21,55 @@ pub struct TunneledHttpResponse {
     pub headers: HashMap<String, String>,
     pub body: Option<String>,
 }
+
+impl TunneledRequest {
+    /// The id of a request that could not be deserialized as a whole, if it has a readable one.
+    pub fn recover_id(text: &str) -> Option<String> {
+        let value: serde_json::Value = serde_json::from_str(text).ok()?;
+        value.get("id")?.as_str().map(str::to_string)
+    }
+
+    /// Splits a JSON text request into the same head and raw body a binary frame carries.
+    pub fn into_head(self) -> Result<(RequestHead, Vec<u8>), base64::DecodeError> {
+        let body = match self.body {
+            Some(body) if !body.is_empty() => general_purpose::STANDARD.decode(body)?,
+            _ => Vec::new(),
+        };
+        let head = RequestHead {
+            id: self.id,
+            method: self.method,
+            path: self.path,
+            headers: self
+                .headers
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect(),
+            query: (!self.query_params.is_empty()).then(|| {
+                form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(&self.query_params)
+                    .finish()
+            }),
+            has_body: !body.is_empty(),
+            authority: None,
+            scheme: None,
+        };
+        Ok((head, body))
+    }
+}
+
+impl TunneledHttpResponse {
+    /// Builds the JSON text representation of a response for servers without binary frames.
+    /// Its header map only keeps the last value of a repeated field.
+    pub fn from_head(head: ResponseHead, body: &[u8]) -> Self {
+        Self {
+            id: head.id,
+            status: head.status,
+            headers: head
+                .headers
+                .into_iter()
+                .map(|(key, value)| (key, value.to_string_lossy()))
+                .collect(),
+            body: Some(general_purpose::STANDARD.encode(body)),
+        }
+    }
+}
@@ -0,0 +1,4 @@
+use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+
+/// The outgoing side of the tunnel connection to the server, see `yats_protocol::outbound`.
+pub type FrameSender = yats_protocol::outbound::FrameSender<WsMessage>;
@@ -0,0 +1,126 @@
+use crate::config::{AppConfig, ReconnectConfig};
+use crate::websocket_handler::{
+    connect_to_websocket, HandshakeRejected, SessionInfo, WsReceiver, WsSender,
+};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Tries to connect to the server again, waiting longer after every failed attempt. The resume
+/// token of the lost session lets the server replace it right away, even if it has not noticed
+/// yet that the old connection is gone. Returns `None` once the attempts are used up, the
+/// server turns the client down for good, or the client is shutting down. With `immediately`,
+/// e.g. after the server announced it is going away, the first attempt is made without delay.
+pub async fn reconnect(
+    config: &AppConfig,
+    resume_token: Option<&str>,
+    reconnect_config: &ReconnectConfig,
+    immediately: bool,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Option<(WsSender, WsReceiver, SessionInfo)> {
+    let limit = match reconnect_config.max_attempts {
+        0 => String::new(),
+        max_attempts => format!("/{}", max_attempts),
+    };
+
+    for attempt in 1.. {
+        if reconnect_config.max_attempts != 0 && attempt > reconnect_config.max_attempts {
+            println!(
+                "❌ Could not reconnect after {} attempts. Giving up.",
+                reconnect_config.max_attempts
+            );
+            return None;
+        }
+
+        let delay = if immediately && attempt == 1 {
+            Duration::ZERO
+        } else {
+            backoff_delay(reconnect_config, attempt)
+        };
+        if delay.is_zero() {
+            println!("🔄 Reconnecting (attempt {}{})...", attempt, limit);
+        } else {
+            println!(
+                "🔄 Reconnecting in {:.1?} (attempt {}{})...",
+                delay, attempt, limit
+            );
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.wait_for(|shutting_down| *shutting_down) => return None,
+        }
+
+        match connect_to_websocket(config, resume_token).await {
+            Ok(connection) => return Some(connection),
+            Err(e) => {
+                warn!("Reconnect attempt {} failed: {}", attempt, e);
+                if let Some(rejected) = e.downcast_ref::<HandshakeRejected>() {
+                    if !rejected.is_transient() {
+                        println!("❌ {}. Not trying again.", rejected);
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Doubles the delay with every attempt up to the configured maximum, then picks a random
+/// point in its upper half so clients that lost the same server do not all come back at once.
+fn backoff_delay(reconnect_config: &ReconnectConfig, attempt: u32) -> Duration {
+    let delay = reconnect_config
+        .base_delay
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(reconnect_config.max_delay);
+    let half = delay / 2;
+    half + half.mul_f64(rand::random::<f64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::http::StatusCode;
+
+    fn reconnect_config() -> ReconnectConfig {
+        ReconnectConfig {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn backoff_delay_doubles_with_jitter_up_to_the_cap() {
+        let config = reconnect_config();
+        for (attempt, full) in [(1, 1), (2, 2), (3, 4), (6, 32), (7, 60), (40, 60)] {
+            let full = Duration::from_secs(full);
+            for _ in 0..50 {
+                let delay = backoff_delay(&config, attempt);
+                assert!(
+                    delay >= full / 2 && delay <= full,
+                    "attempt {}: {:?} is outside {:?}..={:?}",
+                    attempt,
+                    delay,
+                    full / 2,
+                    full
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_some_rejections_are_transient() {
+        let rejected = |status: Option<u16>| HandshakeRejected {
+            status: status.map(|s| StatusCode::from_u16(s).unwrap()),
+            reason: String::new(),
+        };
+        for status in [409, 429, 500, 502, 503] {
+            assert!(rejected(Some(status)).is_transient(), "{}", status);
+        }
+        for status in [400, 401, 403, 404, 426] {
+            assert!(!rejected(Some(status)).is_transient(), "{}", status);
+        }
+        assert!(!rejected(None).is_transient());
+    }
+}
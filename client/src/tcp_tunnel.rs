@@ -0,0 +1,63 @@
+use crate::outbound::FrameSender;
+use crate::protocol::{Frame, MAX_CHUNK_SIZE};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// Connects a visitor's TCP connection to the local TCP service and relays bytes in both
+/// directions until both sides have closed.
+pub async fn open_local_tcp(
+    id: String,
+    target_tcp_address: String,
+    mut data_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    tx: FrameSender,
+) {
+    let stream = match TcpStream::connect(&target_tcp_address).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!(
+                "Failed to connect to local TCP service {} for connection {}: {}",
+                target_tcp_address, id, e
+            );
+            let _ = tx.send_frame(Frame::End { id }).await;
+            return;
+        }
+    };
+    info!(
+        "Opened TCP connection {} to local service {}",
+        id, target_tcp_address
+    );
+
+    let (mut reader, mut writer) = stream.into_split();
+    let upstream = async {
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let frame = Frame::Data {
+                id: id.clone(),
+                chunk: buf[..n].to_vec(),
+            };
+            if tx.send_frame(frame).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send_frame(Frame::End { id: id.clone() }).await;
+    };
+    let downstream = async {
+        while let Some(chunk) = data_rx.recv().await {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+            tx.acknowledge(&id, chunk.len());
+        }
+        let _ = writer.shutdown().await;
+    };
+    tokio::join!(upstream, downstream);
+
+    tx.close_stream(&id);
+    info!("TCP connection {} closed", id);
+}
@@ -0,0 +1,84 @@
+use crate::outbound::FrameSender;
+use crate::protocol::{Frame, MAX_CHUNK_SIZE};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// Closes a flow on our side if the server never does, e.g. after an older server lost track of it.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Relays the datagrams of one visitor flow to the local UDP service and sends its replies back
+/// until the server ends the flow or it goes idle.
+pub async fn open_local_udp(
+    id: String,
+    target_udp_address: String,
+    mut datagram_rx: mpsc::Receiver<Vec<u8>>,
+    tx: FrameSender,
+) {
+    let socket = match connect_local_socket(&target_udp_address).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!(
+                "Failed to open UDP socket to local service {} for flow {}: {}",
+                target_udp_address, id, e
+            );
+            let _ = tx.send_frame(Frame::End { id }).await;
+            return;
+        }
+    };
+    info!(
+        "Opened UDP flow {} to local service {}",
+        id, target_udp_address
+    );
+
+    let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+    loop {
+        tokio::select! {
+            datagram = datagram_rx.recv() => {
+                let Some(datagram) = datagram else {
+                    break;
+                };
+                if let Err(e) = socket.send(&datagram).await {
+                    error!("Failed to send datagram to local service for flow {}: {}", id, e);
+                }
+            }
+            received = socket.recv(&mut buf) => {
+                // Errors such as ICMP port unreachable are transient for UDP.
+                let Ok(n) = received else {
+                    continue;
+                };
+                let frame = Frame::Datagram {
+                    id: id.clone(),
+                    peer_addr: target_udp_address.clone(),
+                    payload: buf[..n].to_vec(),
+                };
+                if tx.send_frame(frame).await.is_err() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep(FLOW_IDLE_TIMEOUT) => {
+                let _ = tx.send_frame(Frame::End { id: id.clone() }).await;
+                break;
+            }
+        }
+    }
+
+    info!("UDP flow {} closed", id);
+}
+
+async fn connect_local_socket(target_udp_address: &str) -> std::io::Result<UdpSocket> {
+    let target = tokio::net::lookup_host(target_udp_address)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other("Address did not resolve"))?;
+    let local: SocketAddr = if target.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
@@ -1,29 +1,124 @@
-use crate::config::AppConfig;
-use crate::http_handler::forward_request_to_local_service;
+use crate::config::{AppConfig, HeartbeatConfig};
+use crate::http_handler::{
+    append_headers, forward_request_to_local_service, stream_request_to_local_service,
+};
 use crate::models::{TunneledHttpResponse, TunneledRequest};
-use futures_util::stream::{SplitSink, SplitStream, StreamExt};
-use reqwest::Client;
+use crate::outbound::FrameSender;
+use crate::protocol::{
+    self, Capabilities, Capability, CompressionStats, ErrorCode, Frame, FrameError, TunnelError,
+};
+use crate::tcp_tunnel::open_local_tcp;
+use crate::udp_tunnel::open_local_udp;
+use crate::websocket_tunnel::{open_local_websocket, WebSocketMessageSender};
+use bytes::Bytes;
+use futures_util::stream::{SplitSink, SplitStream, Stream, StreamExt};
+use futures_util::SinkExt;
+use http_body::Frame as BodyFrame;
+use http_body_util::StreamBody;
+use reqwest::{Body, Client};
+use std::collections::HashMap;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
 use tokio_tungstenite::tungstenite::handshake::client::generate_key;
 use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
 use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tungstenite::handshake::client::Request;
 use tungstenite::http::header::AUTHORIZATION;
-use tungstenite::http::HeaderValue;
+use tungstenite::http::{HeaderMap, HeaderValue, StatusCode};
 use url::Url;
 
 pub type WsSender = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, WsMessage>;
 pub type WsReceiver = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
 
+/// How often the traffic counters of the connection are logged while it is busy.
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Handshake response header carrying the server's protocol version.
+const PROTOCOL_VERSION_HEADER: &str = "x-yats-protocol-version";
+
+/// Handshake response header carrying the capabilities both sides support.
+const CAPABILITIES_HEADER: &str = "x-yats-capabilities";
+
+/// Handshake response header carrying the public port of the TCP tunnel.
+const TCP_PORT_HEADER: &str = "x-yats-tcp-port";
+
+/// Number of datagrams from the server buffered per UDP flow before further ones are dropped.
+const UDP_DATAGRAM_BUFFER: usize = 64;
+
+/// Handshake response header carrying the public port of the UDP tunnel.
+const UDP_PORT_HEADER: &str = "x-yats-udp-port";
+
+/// Handshake response header carrying the host name of the tunnel, when the server gives each
+/// Client ID its own subdomain.
+const TUNNEL_HOST_HEADER: &str = "x-yats-tunnel-host";
+
+/// Handshake header carrying the resume token. The server hands one out with every session,
+/// and sending it back when reconnecting takes over that session if the server still holds it.
+const RESUME_TOKEN_HEADER: &str = "x-yats-resume-token";
+
+/// What the server told us about this session during the handshake.
+#[derive(Debug)]
+pub struct SessionInfo {
+    /// `None` when the server predates protocol negotiation.
+    pub protocol_version: Option<u16>,
+    pub capabilities: Capabilities,
+    pub tcp_port: Option<u16>,
+    pub udp_port: Option<u16>,
+    /// Subdomain the tunnel is also reached at, e.g. `my-app.tunnel.example.com`.
+    pub tunnel_host: Option<String>,
+    /// `None` when the server predates session resumption.
+    pub resume_token: Option<String>,
+}
+
+/// The server turned down the handshake, or speaks a protocol this client cannot use.
+#[derive(Debug)]
+pub struct HandshakeRejected {
+    /// `None` when the handshake succeeded but the protocol versions do not match.
+    pub status: Option<StatusCode>,
+    pub reason: String,
+}
+
+impl HandshakeRejected {
+    /// Whether trying again later may succeed, e.g. while the server still holds on to our
+    /// previous connection or is overloaded. A bad token or protocol mismatch will not go away.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.status.map(|s| s.as_u16()), Some(409 | 429 | 500..=599))
+    }
+}
+
+impl std::fmt::Display for HandshakeRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(
+                f,
+                "Server rejected the connection ({}): {}",
+                status, self.reason
+            ),
+            None => f.write_str(&self.reason),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeRejected {}
+
 pub async fn connect_to_websocket(
     config: &AppConfig,
-) -> Result<(WsSender, WsReceiver), Box<dyn std::error::Error>> {
+    resume_token: Option<&str>,
+) -> Result<(WsSender, WsReceiver, SessionInfo), Box<dyn std::error::Error>> {
     let mut ws_url = Url::parse(&config.server_ws_url)?;
     ws_url
         .query_pairs_mut()
         .append_pair("client_id", &config.client_id);
+    ws_url
+        .query_pairs_mut()
+        .append_pair("protocol_version", &protocol::PROTOCOL_VERSION.to_string());
+    ws_url
+        .query_pairs_mut()
+        .append_pair("capabilities", &client_capabilities().to_string());
 
     // The server should handle empty paths correctly, so we always send the parameter.
     ws_url
@@ -36,6 +131,30 @@ pub async fn connect_to_websocket(
             .append_pair("allowed_ips", &config.allowed_ips.join(","));
     }
 
+    if config.target_tcp_address.is_some() {
+        ws_url.query_pairs_mut().append_pair("tcp_tunnel", "true");
+    }
+
+    if config.target_udp_address.is_some() {
+        ws_url.query_pairs_mut().append_pair("udp_tunnel", "true");
+    }
+
+    if let Some(load_balancing) = &config.load_balancing {
+        ws_url
+            .query_pairs_mut()
+            .append_pair("balancing", load_balancing.strategy.as_str())
+            .append_pair("weight", &load_balancing.weight.to_string());
+        if let Some(affinity) = &load_balancing.affinity {
+            ws_url.query_pairs_mut().append_pair("affinity", affinity);
+        }
+    }
+
+    if !config.custom_domains.is_empty() {
+        ws_url
+            .query_pairs_mut()
+            .append_pair("domains", &config.custom_domains.join(","));
+    }
+
     if !config.allowed_asns.is_empty() {
         ws_url.query_pairs_mut().append_pair(
             "allowed_asns",
@@ -51,7 +170,7 @@ pub async fn connect_to_websocket(
     let auth_header_value = format!("Bearer {}", config.secret_token);
     let host = ws_url.host_str().ok_or("Invalid WebSocket URL: no host")?;
 
-    let request = Request::builder()
+    let mut request = Request::builder()
         .method("GET")
         .uri(ws_url.as_str())
         .header("Host", host)
@@ -59,28 +178,200 @@ pub async fn connect_to_websocket(
         .header("Connection", "upgrade")
         .header("Sec-Websocket-Key", generate_key())
         .header("Sec-Websocket-Version", "13")
-        .header(AUTHORIZATION, HeaderValue::from_str(&auth_header_value)?)
-        .body(())?;
+        .header(AUTHORIZATION, HeaderValue::from_str(&auth_header_value)?);
+    if let Some(resume_token) = resume_token {
+        request = request.header(RESUME_TOKEN_HEADER, HeaderValue::from_str(resume_token)?);
+    }
+    let request = request.body(())?;
 
     info!("Connecting to WebSocket server at {}", ws_url);
 
-    let (ws_stream, response) = connect_async(request).await?;
+    let (ws_stream, response) = match connect_async(request).await {
+        Ok(connected) => connected,
+        Err(tungstenite::Error::Http(response)) => {
+            let reason = response
+                .body()
+                .as_deref()
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default()
+                .into_owned();
+            return Err(HandshakeRejected {
+                status: Some(response.status()),
+                reason,
+            }
+            .into());
+        }
+        Err(e) => return Err(e.into()),
+    };
 
     debug!("Server response during handshake: {:?}", response);
     info!("WebSocket connection established!");
 
-    Ok(ws_stream.split())
+    let protocol_version = response
+        .headers()
+        .get(PROTOCOL_VERSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u16>().ok());
+    if let Some(version) = protocol_version {
+        if version < protocol::MIN_PROTOCOL_VERSION {
+            return Err(HandshakeRejected {
+                status: None,
+                reason: format!(
+                    "Server speaks protocol version {}, but this client needs at least version {}. Please upgrade the server.",
+                    version,
+                    protocol::MIN_PROTOCOL_VERSION
+                ),
+            }
+            .into());
+        }
+    }
+    let capabilities = response
+        .headers()
+        .get(CAPABILITIES_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(Capabilities::parse)
+        .unwrap_or_default()
+        .intersection(&client_capabilities());
+    match protocol_version {
+        Some(version) => info!(
+            "Server speaks protocol version {} with capabilities: {}",
+            version, capabilities
+        ),
+        None => info!("Server predates protocol negotiation. Using the JSON text protocol."),
+    }
+
+    let session = SessionInfo {
+        protocol_version,
+        capabilities,
+        tcp_port: response
+            .headers()
+            .get(TCP_PORT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+        udp_port: response
+            .headers()
+            .get(UDP_PORT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+        tunnel_host: response
+            .headers()
+            .get(TUNNEL_HOST_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        resume_token: response
+            .headers()
+            .get(RESUME_TOKEN_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+    };
+
+    let (ws_sender, ws_receiver) = ws_stream.split();
+    Ok((ws_sender, ws_receiver, session))
+}
+
+/// Optional features this client supports.
+fn client_capabilities() -> Capabilities {
+    Capabilities::from_iter([
+        Capability::BinaryFrames,
+        Capability::Streaming,
+        Capability::Compression,
+        Capability::WebSocket,
+        Capability::Tcp,
+        Capability::Udp,
+        Capability::Cancel,
+        Capability::Errors,
+        Capability::GoAway,
+        Capability::Trailers,
+    ])
 }
 
+/// Writes queued messages to the server in the order the scheduler picks them, compressing
+/// binary frames when negotiated.
+pub async fn send_websocket_messages(mut ws_sender: WsSender, rx: FrameSender, compress: bool) {
+    let mut stats = CompressionStats::default();
+    let mut logged_stats = stats;
+    let mut stats_interval = tokio::time::interval(STATS_LOG_INTERVAL);
+
+    loop {
+        tokio::select! {
+            message = rx.next() => {
+                let message = match message {
+                    WsMessage::Binary(frame) => {
+                        let uncompressed_len = frame.len();
+                        let frame = if compress { protocol::compress_frame(frame) } else { frame };
+                        stats.record(uncompressed_len, frame.len());
+                        WsMessage::Binary(frame)
+                    }
+                    other => other,
+                };
+                if let Err(e) = ws_sender.send(message).await {
+                    error!("Failed to send message over WebSocket: {:?}", e);
+                    break;
+                }
+            }
+            _ = stats_interval.tick() => {
+                if stats != logged_stats {
+                    info!("Frames sent to the server so far: {}", stats);
+                    logged_stats = stats;
+                }
+            }
+        }
+    }
+
+    info!("Frames sent to the server in total: {}", stats);
+    info!("WebSocket sender task shutting down.");
+}
+
+/// Serves requests from the server until the connection ends. Returns whether the server sent
+/// a `GoAway` before, in which case it is worth reconnecting right away.
 pub async fn handle_websocket_messages(
     mut ws_receiver: WsReceiver,
-    tx: mpsc::Sender<WsMessage>,
+    tx: FrameSender,
     http_client: Client,
     config: AppConfig,
-) {
+    capabilities: Capabilities,
+    heartbeat_config: HeartbeatConfig,
+) -> bool {
+    // Request bodies still being streamed to the local service, keyed by request id. Like the
+    // other per-stream buffers, they are bounded by the server's send window. `None` marks the
+    // `End` of a body.
+    let mut request_bodies: HashMap<String, mpsc::UnboundedSender<Option<BodyFrame<Bytes>>>> =
+        HashMap::new();
+    // Tunnelled WebSockets relayed to the local service, keyed by request id.
+    let mut websocket_streams: HashMap<String, WebSocketMessageSender> = HashMap::new();
+    // Tunnelled TCP connections to the local TCP service, keyed by connection id.
+    let mut tcp_connections: HashMap<String, mpsc::UnboundedSender<Vec<u8>>> = HashMap::new();
+    // UDP flows to the local UDP service, keyed by flow id.
+    let mut udp_flows: HashMap<String, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    // Tasks working on a request or WebSocket handshake, so the server can cancel them.
+    let mut in_flight: HashMap<String, AbortHandle> = HashMap::new();
+    let mut heartbeat = tokio::time::interval_at(
+        Instant::now() + heartbeat_config.ping_interval,
+        heartbeat_config.ping_interval,
+    );
+    // Set while a ping is unanswered. Any message from the server counts as an answer.
+    let mut pong_deadline: Option<Instant> = None;
+    let mut went_away = false;
+
     loop {
         tokio::select! {
+            _ = heartbeat.tick() => {
+                if pong_deadline.is_none() {
+                    pong_deadline = Some(Instant::now() + heartbeat_config.pong_timeout);
+                    let _ = tx.send_message(WsMessage::Ping(Vec::new()));
+                }
+            }
+            _ = tokio::time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                warn!(
+                    "No answer from the server within {:?} of a ping. Closing the connection.",
+                    heartbeat_config.pong_timeout
+                );
+                break;
+            }
             message = ws_receiver.next() => {
+                if matches!(message, Some(Ok(_))) {
+                    pong_deadline = None;
+                }
                 match message {
                     Some(Ok(WsMessage::Text(text))) => {
                         info!("Received text from server: {}", text);
@@ -89,41 +380,210 @@ pub async fn handle_websocket_messages(
                         let config_clone = config.clone();
 
                         tokio::spawn(async move {
-                            match serde_json::from_str::<TunneledRequest>(&text) {
+                            let result = match serde_json::from_str::<TunneledRequest>(&text) {
                                 Ok(tunneled_req) => {
-                                    let response = forward_request_to_local_service(&http_client_clone, tunneled_req, &config_clone.target_http_service_url).await;
-                                    match serde_json::to_string(&response) {
-                                        Ok(json_payload) => {
-                                            if let Err(e) = tx_clone.send(WsMessage::Text(json_payload)).await {
-                                                error!("Failed to send response back to server (ID: {}): {:?}", response.id, e);
-                                            }
-                                        }
+                                    let id = tunneled_req.id.clone();
+                                    match tunneled_req.into_head() {
+                                        Ok((head, body)) => Ok(forward_request_to_local_service(&http_client_clone, head, body, &config_clone.target_http_service_url).await),
                                         Err(e) => {
-                                            error!("Failed to serialize response (ID: {}): {:?}", response.id, e);
+                                            error!("Failed to base64 decode request body for ID {}: {}", id, e);
+                                            Err(TunnelError {
+                                                id: Some(id),
+                                                code: ErrorCode::InvalidRequest,
+                                                message: format!("Failed to decode request body: {}", e),
+                                            })
                                         }
                                     }
                                 }
                                 Err(e) => {
                                     error!("Failed to deserialize request from server: {}", e);
-                                    let err_resp = TunneledHttpResponse {
-                                        id: "unknown".to_string(),
-                                        status: 400,
-                                        headers: std::collections::HashMap::new(),
-                                        body: Some(format!("Failed to deserialize request: {}", e)),
-                                    };
-                                     if let Ok(json_payload) = serde_json::to_string(&err_resp) {
-                                        if let Err(e) = tx_clone.send(WsMessage::Text(json_payload)).await {
-                                            error!("Failed to send deserialization error back to server: {:?}", e);
-                                        }
+                                    Err(TunnelError {
+                                        id: TunneledRequest::recover_id(&text),
+                                        code: ErrorCode::MalformedMessage,
+                                        message: format!("Failed to deserialize request: {}", e),
+                                    })
+                                }
+                            };
+
+                            let (id, json_payload) = match result {
+                                Ok((response_head, response_body)) => {
+                                    let response = TunneledHttpResponse::from_head(response_head, &response_body);
+                                    (Some(response.id.clone()), serde_json::to_string(&response))
+                                }
+                                Err(error) => (error.id.clone(), serde_json::to_string(&error)),
+                            };
+                            match json_payload {
+                                Ok(json_payload) => {
+                                    if tx_clone.send_message(WsMessage::Text(json_payload)).is_err() {
+                                        error!("Failed to send response back to server (ID: {:?})", id);
                                     }
                                 }
+                                Err(e) => {
+                                    error!("Failed to serialize response (ID: {:?}): {:?}", id, e);
+                                }
                             }
                         });
                     }
+                    Some(Ok(WsMessage::Binary(bin))) => {
+                        match Frame::decode(&bin) {
+                            Ok(Frame::Request(head)) => {
+                                info!("Received binary request frame for ID: {}", head.id);
+                                let body = if head.has_body {
+                                    let (body_tx, body_rx) = mpsc::unbounded_channel();
+                                    request_bodies.insert(head.id.clone(), body_tx);
+                                    let body = RequestBody {
+                                        body_rx,
+                                        tx: tx.clone(),
+                                        id: head.id.clone(),
+                                    };
+                                    Some(Body::wrap(StreamBody::new(body.into_stream())))
+                                } else {
+                                    None
+                                };
+
+                                // Forget tasks that have finished since the last request.
+                                in_flight.retain(|_, task| !task.is_finished());
+                                let id = head.id.clone();
+                                let tx_clone = tx.clone();
+                                let http_client_clone = http_client.clone();
+                                let config_clone = config.clone();
+                                let capabilities = capabilities.clone();
+                                let task = tokio::spawn(async move {
+                                    stream_request_to_local_service(&http_client_clone, head, body, &config_clone.target_http_service_url, tx_clone, capabilities).await;
+                                });
+                                in_flight.insert(id, task.abort_handle());
+                            }
+                            Ok(Frame::Data { id, chunk }) => {
+                                // Chunks nobody reads anymore still hand back their credit.
+                                let len = chunk.len();
+                                if let Some(body_tx) = request_bodies.get(&id) {
+                                    if body_tx.send(Some(BodyFrame::data(chunk.into()))).is_err() {
+                                        info!("Local service stopped reading the request body for ID: {}", id);
+                                        request_bodies.remove(&id);
+                                        tx.acknowledge(&id, len);
+                                    }
+                                } else if tcp_connections.get(&id).is_none_or(|data_tx| data_tx.send(chunk).is_err()) {
+                                    tcp_connections.remove(&id);
+                                    tx.acknowledge(&id, len);
+                                }
+                            }
+                            Ok(Frame::WindowUpdate { id, increment }) => {
+                                tx.grant(&id, increment);
+                            }
+                            Ok(Frame::Trailers { id, headers }) => {
+                                if let Some(body_tx) = request_bodies.get(&id) {
+                                    let mut trailers = HeaderMap::new();
+                                    append_headers(&mut trailers, headers, &id);
+                                    let _ = body_tx.send(Some(BodyFrame::trailers(trailers)));
+                                }
+                            }
+                            Ok(Frame::End { id }) => {
+                                if let Some(body_tx) = request_bodies.remove(&id) {
+                                    let _ = body_tx.send(None);
+                                }
+                                websocket_streams.remove(&id);
+                                tcp_connections.remove(&id);
+                                udp_flows.remove(&id);
+                            }
+                            Ok(Frame::Cancel { id }) => {
+                                request_bodies.remove(&id);
+                                websocket_streams.remove(&id);
+                                tx.close_stream(&id);
+                                match in_flight.remove(&id) {
+                                    Some(task) if !task.is_finished() => {
+                                        task.abort();
+                                        info!("Cancelled request ID {} at the server's request", id);
+                                    }
+                                    _ => info!("Server cancelled request ID {}, which had already finished", id),
+                                }
+                            }
+                            Ok(Frame::Datagram { id, peer_addr, payload }) => {
+                                let Some(target_udp_address) = config.target_udp_address.clone() else {
+                                    warn!("Received UDP flow {} but no local UDP service is configured", id);
+                                    let _ = tx.send_frame(Frame::End { id }).await;
+                                    continue;
+                                };
+                                let datagram_tx = match udp_flows.get(&id) {
+                                    Some(datagram_tx) if !datagram_tx.is_closed() => datagram_tx,
+                                    _ => {
+                                        info!("Received UDP flow {} from {}", id, peer_addr);
+                                        // Forget flows that have finished since the last one opened.
+                                        udp_flows.retain(|_, datagram_tx| !datagram_tx.is_closed());
+                                        let (datagram_tx, datagram_rx) = mpsc::channel(UDP_DATAGRAM_BUFFER);
+                                        tokio::spawn(open_local_udp(id.clone(), target_udp_address, datagram_rx, tx.clone()));
+                                        udp_flows.entry(id).or_insert(datagram_tx)
+                                    }
+                                };
+                                // Datagrams may be lost anyway, so never stall the tunnel for one.
+                                let _ = datagram_tx.try_send(payload);
+                            }
+                            Ok(Frame::TcpOpen { id, peer_addr }) => {
+                                info!("Received TCP connection {} from {}", id, peer_addr);
+                                let Some(target_tcp_address) = config.target_tcp_address.clone() else {
+                                    warn!("Received TCP connection {} but no local TCP service is configured", id);
+                                    let _ = tx.send_frame(Frame::End { id }).await;
+                                    continue;
+                                };
+                                // Forget connections that have finished since the last one opened.
+                                tcp_connections.retain(|_, data_tx| !data_tx.is_closed());
+                                let (data_tx, data_rx) = mpsc::unbounded_channel();
+                                tcp_connections.insert(id.clone(), data_tx);
+                                tokio::spawn(open_local_tcp(id, target_tcp_address, data_rx, tx.clone()));
+                            }
+                            Ok(Frame::WebSocketOpen(head)) => {
+                                info!("Received WebSocket open frame for ID: {}", head.id);
+                                // Forget relays that have finished since the last upgrade.
+                                websocket_streams.retain(|_, sender| !sender.is_closed());
+                                let (message_tx, message_rx) = mpsc::unbounded_channel();
+                                websocket_streams.insert(head.id.clone(), message_tx);
+
+                                in_flight.retain(|_, task| !task.is_finished());
+                                let id = head.id.clone();
+                                let tx_clone = tx.clone();
+                                let config_clone = config.clone();
+                                let task = tokio::spawn(async move {
+                                    open_local_websocket(head, &config_clone.target_http_service_url, message_rx, tx_clone).await;
+                                });
+                                in_flight.insert(id, task.abort_handle());
+                            }
+                            Ok(Frame::WebSocketMessage { id, kind, payload }) => {
+                                let len = payload.len();
+                                if websocket_streams.get(&id).is_none_or(|sender| sender.send((kind, payload)).is_err()) {
+                                    websocket_streams.remove(&id);
+                                    tx.acknowledge(&id, len);
+                                }
+                            }
+                            Ok(Frame::GoAway(go_away)) => {
+                                println!(
+                                    "\n⚠️ {} Finishing requests in flight (up to {}s) before reconnecting.",
+                                    go_away.message, go_away.drain_timeout_secs
+                                );
+                                went_away = true;
+                            }
+                            Ok(frame) => {
+                                warn!("Received unexpected frame from server: {:?}", frame);
+                            }
+                            Err(e) => {
+                                error!("Failed to decode binary frame from server: {}", e);
+                                if capabilities.contains(Capability::Errors) {
+                                    let code = match e {
+                                        FrameError::UnknownType(_) => ErrorCode::UnsupportedFrame,
+                                        _ => ErrorCode::MalformedMessage,
+                                    };
+                                    let error = TunnelError {
+                                        id: protocol::frame_id(&bin),
+                                        code,
+                                        message: e.to_string(),
+                                    };
+                                    let _ = tx.send_frame(Frame::Error(error)).await;
+                                }
+                            }
+                        }
+                    }
                     Some(Ok(WsMessage::Ping(data))) => {
                         debug!("Received PING from server. Sending PONG.");
-                        if let Err(e) = tx.send(WsMessage::Pong(data)).await {
-                            error!("Failed to send PONG to server: {:?}", e);
+                        if tx.send_message(WsMessage::Pong(data)).is_err() {
+                            error!("Failed to send PONG to server");
                             break;
                         }
                     }
@@ -144,4 +604,57 @@ pub async fn handle_websocket_messages(
             }
         }
     }
+    // Requests still running cannot be answered anymore, and their uploads will not finish.
+    for task in in_flight.values() {
+        task.abort();
+    }
+    went_away
+}
+
+/// A request body on its way to the local service. The server gets more credit as the local
+/// service takes each chunk. If the local service stops reading early, whatever is still
+/// buffered is handed back as credit too, so the server's upload is not left waiting.
+struct RequestBody {
+    body_rx: mpsc::UnboundedReceiver<Option<BodyFrame<Bytes>>>,
+    tx: FrameSender,
+    id: String,
+}
+
+impl RequestBody {
+    /// Turns the body into a stream for `StreamBody`. A body that is dropped before its `End`
+    /// frame, e.g. because the tunnel connection went down, ends with an error, so the local
+    /// service does not take a cut off upload for a complete one.
+    fn into_stream(self) -> impl Stream<Item = Result<BodyFrame<Bytes>, std::io::Error>> {
+        futures_util::stream::unfold(Some(self), |body| async move {
+            let mut body = body?;
+            match body.body_rx.recv().await {
+                Some(Some(frame)) => {
+                    if let Some(chunk) = frame.data_ref() {
+                        body.tx.acknowledge(&body.id, chunk.len());
+                    }
+                    Some((Ok(frame), Some(body)))
+                }
+                Some(None) => None,
+                None => Some((
+                    Err(std::io::Error::other("the request body was cut off")),
+                    None,
+                )),
+            }
+        })
+    }
+}
+
+impl Drop for RequestBody {
+    fn drop(&mut self) {
+        // Chunks arriving from now on are acknowledged by the message loop.
+        self.body_rx.close();
+        while let Ok(frame) = self.body_rx.try_recv() {
+            let Some(frame) = frame else {
+                continue;
+            };
+            if let Some(chunk) = frame.data_ref() {
+                self.tx.acknowledge(&self.id, chunk.len());
+            }
+        }
+    }
 }
@@ -0,0 +1,195 @@
+use crate::http_handler::{append_headers, error_response, headers_to_list};
+use crate::outbound::FrameSender;
+use crate::protocol::{BufferedResponse, Frame, RequestHead, ResponseHead, WebSocketMessageKind};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+use tracing::{error, info, warn};
+use url::Url;
+
+/// Sender half used by the message loop to hand server-side messages to a relay task. The
+/// server's send window bounds how much can be queued, so it never blocks.
+pub type WebSocketMessageSender = mpsc::UnboundedSender<(WebSocketMessageKind, Vec<u8>)>;
+
+/// Handshake and hop-by-hop headers that tungstenite generates itself for the local handshake.
+const SKIPPED_HEADERS: [&str; 10] = [
+    "host",
+    "connection",
+    "upgrade",
+    "keep-alive",
+    "te",
+    "transfer-encoding",
+    "content-length",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+];
+
+/// Opens a WebSocket to the local service on behalf of a visitor and relays messages between
+/// it and the server until either side closes. The outcome of the local handshake is sent back
+/// as a `Response` frame, with status 101 on success.
+pub async fn open_local_websocket(
+    head: RequestHead,
+    target_http_service_url: &str,
+    mut message_rx: mpsc::UnboundedReceiver<(WebSocketMessageKind, Vec<u8>)>,
+    tx: FrameSender,
+) {
+    let id = head.id.clone();
+    let request = match build_local_request(&head, target_http_service_url) {
+        Ok(request) => request,
+        Err(e) => {
+            error!(
+                "Failed to build local WebSocket request for ID {}: {}",
+                id, e
+            );
+            send_error_response(&tx, error_response(id, 502, "Bad Gateway")).await;
+            return;
+        }
+    };
+
+    info!(
+        "Opening WebSocket (ID: {}) to local service: {}",
+        id,
+        request.uri()
+    );
+
+    let (local_stream, response) = match connect_async(request).await {
+        Ok(connected) => connected,
+        Err(tungstenite::Error::Http(response)) => {
+            warn!(
+                "Local service refused WebSocket upgrade for ID {} with status {}",
+                id,
+                response.status()
+            );
+            let head = ResponseHead {
+                id: id.clone(),
+                status: response.status().as_u16(),
+                headers: headers_to_list(response.headers()),
+            };
+            let body = response.into_body().unwrap_or_default();
+            send_error_response(&tx, (head, body)).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to open local WebSocket for ID {}: {}", id, e);
+            send_error_response(&tx, error_response(id, 502, "Bad Gateway")).await;
+            return;
+        }
+    };
+
+    let head = ResponseHead {
+        id: id.clone(),
+        status: 101,
+        headers: headers_to_list(response.headers()),
+    };
+    if tx.send_frame(Frame::Response(head)).await.is_err() {
+        return;
+    }
+
+    let (mut local_sink, mut local_stream) = local_stream.split();
+    let mut close_sent = false;
+    loop {
+        tokio::select! {
+            message = local_stream.next() => {
+                let (kind, payload) = match message {
+                    Some(Ok(WsMessage::Text(text))) => (WebSocketMessageKind::Text, text.into_bytes()),
+                    Some(Ok(WsMessage::Binary(bin))) => (WebSocketMessageKind::Binary, bin),
+                    Some(Ok(WsMessage::Close(close_frame))) => {
+                        let (code, reason) = close_frame
+                            .map(|f| (Some(f.code.into()), f.reason.into_owned()))
+                            .unwrap_or_default();
+                        (WebSocketMessageKind::Close { code, reason }, Vec::new())
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => {
+                        if close_sent {
+                            break;
+                        }
+                        let _ = tx.send_frame(Frame::End { id: id.clone() }).await;
+                        break;
+                    }
+                };
+
+                // Keep reading after a close so the automatic close reply gets flushed.
+                close_sent |= matches!(kind, WebSocketMessageKind::Close { .. });
+                let frame = Frame::WebSocketMessage { id: id.clone(), kind, payload };
+                if tx.send_frame(frame).await.is_err() {
+                    break;
+                }
+            }
+            message = message_rx.recv() => {
+                let len = message.as_ref().map_or(0, |(_, payload)| payload.len());
+                let message = match message {
+                    Some((WebSocketMessageKind::Text, payload)) => {
+                        WsMessage::Text(String::from_utf8_lossy(&payload).into_owned())
+                    }
+                    Some((WebSocketMessageKind::Binary, payload)) => WsMessage::Binary(payload),
+                    Some((WebSocketMessageKind::Close { code, reason }, _)) => {
+                        let _ = local_sink
+                            .send(WsMessage::Close(code.map(|code| CloseFrame {
+                                code: code.into(),
+                                reason: reason.into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    None => {
+                        let _ = local_sink.close().await;
+                        break;
+                    }
+                };
+
+                if local_sink.send(message).await.is_err() {
+                    let _ = tx.send_frame(Frame::End { id: id.clone() }).await;
+                    break;
+                }
+                tx.acknowledge(&id, len);
+            }
+        }
+    }
+
+    tx.close_stream(&id);
+    info!("Local WebSocket closed for ID: {}", id);
+}
+
+fn build_local_request(
+    head: &RequestHead,
+    target_http_service_url: &str,
+) -> Result<tungstenite::handshake::client::Request, Box<dyn std::error::Error + Send + Sync>> {
+    let mut url = Url::parse(&format!("{}{}", target_http_service_url, head.path))?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    url.set_scheme(scheme)
+        .map_err(|_| "Failed to set WebSocket URL scheme")?;
+    url.set_query(head.query.as_deref());
+
+    let mut request = url.as_str().into_client_request()?;
+    let headers = head
+        .headers
+        .iter()
+        .filter(|(key, _)| !SKIPPED_HEADERS.contains(&key.to_ascii_lowercase().as_str()))
+        .cloned()
+        .collect();
+    append_headers(request.headers_mut(), headers, &head.id);
+    Ok(request)
+}
+
+/// Sends a non-101 outcome of the local handshake as an ordinary response.
+async fn send_error_response(tx: &FrameSender, (head, body): BufferedResponse) {
+    let id = head.id.clone();
+    if tx.send_frame(Frame::Response(head)).await.is_err() {
+        return;
+    }
+    if !body.is_empty() {
+        let frame = Frame::Data {
+            id: id.clone(),
+            chunk: body,
+        };
+        if tx.send_frame(frame).await.is_err() {
+            return;
+        }
+    }
+    let _ = tx.send_frame(Frame::End { id }).await;
+}
@@ -0,0 +1,1005 @@
+//! The wire format of the tunnel connection, shared by the server and the client.
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub mod outbound;
+
+/// Version of the binary frame layout, the first byte of every frame.
+pub const FRAME_VERSION: u8 = 1;
+
+/// version (1 byte) + frame type (1 byte) + header length (4 bytes, big endian).
+const PREAMBLE_LEN: usize = 6;
+
+const FRAME_TYPE_REQUEST: u8 = 1;
+const FRAME_TYPE_RESPONSE: u8 = 2;
+const FRAME_TYPE_DATA: u8 = 3;
+const FRAME_TYPE_END: u8 = 4;
+const FRAME_TYPE_WEBSOCKET_OPEN: u8 = 5;
+const FRAME_TYPE_WEBSOCKET_MESSAGE: u8 = 6;
+const FRAME_TYPE_TCP_OPEN: u8 = 7;
+const FRAME_TYPE_DATAGRAM: u8 = 8;
+const FRAME_TYPE_CANCEL: u8 = 9;
+const FRAME_TYPE_WINDOW_UPDATE: u8 = 10;
+const FRAME_TYPE_ERROR: u8 = 11;
+const FRAME_TYPE_GO_AWAY: u8 = 12;
+const FRAME_TYPE_TRAILERS: u8 = 13;
+
+/// Set in the frame type byte when the body is zstd compressed.
+const FLAG_COMPRESSED: u8 = 0x80;
+
+/// Bodies smaller than this are sent as they are; compressing them is not worth the CPU.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Upper bound for a decompressed body, matching the WebSocket message size limit.
+const MAX_DECOMPRESSED_LEN: usize = 64 << 20;
+
+/// Upper bound for the payload of a single `Data` frame. Larger chunks are split.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Payload bytes a stream may have in flight before the receiver grants more credit.
+pub const STREAM_WINDOW: usize = 512 * 1024;
+
+/// Version of the tunnel protocol as a whole, exchanged as `protocol_version` in the `/ws`
+/// handshake. Only bumped for changes that cannot be expressed as an optional capability.
+/// Version 2 carries headers as an ordered list instead of a map, version 3 the raw query
+/// string instead of parsed parameters, version 4 header values as raw bytes, version 5
+/// per-stream flow control with `WindowUpdate` frames.
+pub const PROTOCOL_VERSION: u16 = 5;
+
+/// Oldest protocol version of the other side that this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
+
+/// An optional feature of the tunnel. Features are only used when both sides list them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    BinaryFrames,
+    Streaming,
+    Compression,
+    WebSocket,
+    Tcp,
+    Udp,
+    Cancel,
+    Errors,
+    GoAway,
+    Trailers,
+}
+
+impl Capability {
+    const ALL: [Capability; 10] = [
+        Capability::BinaryFrames,
+        Capability::Streaming,
+        Capability::Compression,
+        Capability::WebSocket,
+        Capability::Tcp,
+        Capability::Udp,
+        Capability::Cancel,
+        Capability::Errors,
+        Capability::GoAway,
+        Capability::Trailers,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Capability::BinaryFrames => "binary_frames",
+            Capability::Streaming => "streaming",
+            Capability::Compression => "compression",
+            Capability::WebSocket => "websocket",
+            Capability::Tcp => "tcp",
+            Capability::Udp => "udp",
+            Capability::Cancel => "cancel",
+            Capability::Errors => "errors",
+            Capability::GoAway => "go_away",
+            Capability::Trailers => "trailers",
+        }
+    }
+}
+
+/// A set of capabilities, written as comma separated names in the handshake. Unknown names
+/// are ignored, so newer peers can advertise features this build does not know about.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(HashSet<Capability>);
+
+impl Capabilities {
+    pub fn parse(names: &str) -> Self {
+        let names: HashSet<&str> = names.split(',').map(str::trim).collect();
+        Self(
+            Capability::ALL
+                .into_iter()
+                .filter(|capability| names.contains(capability.as_str()))
+                .collect(),
+        )
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0.contains(&capability)
+    }
+
+    /// The capabilities both sides support.
+    pub fn intersection(&self, other: &Capabilities) -> Capabilities {
+        Self(self.0.intersection(&other.0).copied().collect())
+    }
+}
+
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Capability::ALL
+            .into_iter()
+            .filter(|capability| self.contains(*capability))
+            .map(Capability::as_str)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Header fields in the order they were received. Names may repeat, e.g. for `Set-Cookie`.
+pub type Headers = Vec<(String, HeaderBytes)>;
+
+/// The raw bytes of a header value. Serialized as a JSON string when they are valid UTF-8 and
+/// as `{"base64": "..."}` otherwise, so opaque values survive the JSON header block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderBytes(pub Vec<u8>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum HeaderBytesRepr<'a> {
+    Text(Cow<'a, str>),
+    Base64 { base64: String },
+}
+
+impl Serialize for HeaderBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match std::str::from_utf8(&self.0) {
+            Ok(text) => HeaderBytesRepr::Text(Cow::Borrowed(text)),
+            Err(_) => HeaderBytesRepr::Base64 {
+                base64: general_purpose::STANDARD.encode(&self.0),
+            },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match HeaderBytesRepr::deserialize(deserializer)? {
+            HeaderBytesRepr::Text(text) => Ok(HeaderBytes(text.into_owned().into_bytes())),
+            HeaderBytesRepr::Base64 { base64 } => general_purpose::STANDARD
+                .decode(base64)
+                .map(HeaderBytes)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl From<String> for HeaderBytes {
+    fn from(value: String) -> Self {
+        HeaderBytes(value.into_bytes())
+    }
+}
+
+impl HeaderBytes {
+    /// The value as text for the JSON text protocol, which can only carry strings.
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0).into_owned()
+    }
+}
+
+/// Header fields this side could not rebuild from what the other side sent.
+static DROPPED_HEADERS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a dropped header field and returns how many have been dropped since startup.
+pub fn record_dropped_header() -> u64 {
+    DROPPED_HEADERS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Request metadata that opens a stream. When `has_body` is set, the body follows as
+/// `Data` frames terminated by an `End` frame; otherwise no body frames are sent.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestHead {
+    pub id: String,
+    pub method: String,
+    pub path: String,
+    pub headers: Headers,
+    /// The query string exactly as the visitor sent it, without the leading `?`.
+    pub query: Option<String>,
+    pub has_body: bool,
+    /// The visitor's `:authority`, or `Host` on HTTP/1.1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authority: Option<String>,
+    /// The visitor's `:scheme`, `http` or `https`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+}
+
+/// Response metadata. The body always follows as `Data` frames terminated by an `End` frame.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseHead {
+    pub id: String,
+    pub status: u16,
+    pub headers: Headers,
+}
+
+/// A response head together with its fully buffered body, as the JSON text protocol carries it.
+pub type BufferedResponse = (ResponseHead, Vec<u8>);
+
+/// Kind of a message relayed over a tunnelled WebSocket. Pings and pongs are answered on
+/// each hop and are not relayed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum WebSocketMessageKind {
+    Text,
+    Binary,
+    Close { code: Option<u16>, reason: String },
+}
+
+/// Header block of `WebSocketMessage` frames.
+#[derive(Serialize, Deserialize, Debug)]
+struct WebSocketMessageHeader {
+    id: String,
+    kind: WebSocketMessageKind,
+}
+
+/// Header block of `TcpOpen` and `Datagram` frames.
+#[derive(Serialize, Deserialize, Debug)]
+struct PeerHeader {
+    id: String,
+    peer_addr: String,
+}
+
+/// Why a message could not be handled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message could not be decoded at all.
+    MalformedMessage,
+    /// The message was decoded, but its contents are invalid, e.g. a body that is not base64.
+    InvalidRequest,
+    /// The frame type is not known to the receiver.
+    UnsupportedFrame,
+    /// The local service failed while answering, e.g. its response body broke off.
+    UpstreamFailed,
+    /// A code this build does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Reports a message the receiver could not handle. `id` is the request it belongs to, if
+/// that could be recovered from the broken message. Carried in an `Error` frame, or as a JSON
+/// text message on the JSON text protocol.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TunnelError {
+    pub id: Option<String>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Tells the client that the server is shutting down and it should connect again, e.g. to
+/// another instance. Requests already in flight may finish for `drain_timeout_secs`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GoAway {
+    pub message: String,
+    pub drain_timeout_secs: u64,
+}
+
+/// Header block of `WindowUpdate` frames.
+#[derive(Serialize, Deserialize, Debug)]
+struct WindowUpdateHeader {
+    id: String,
+    increment: u32,
+}
+
+/// Header block of `Trailers` frames.
+#[derive(Serialize, Deserialize, Debug)]
+struct TrailersHeader {
+    id: String,
+    headers: Headers,
+}
+
+/// Header block of `Data`, `End` and `Cancel` frames.
+#[derive(Serialize, Deserialize, Debug)]
+struct StreamHeader {
+    id: String,
+}
+
+/// A single tunnel message carried in a WebSocket binary message.
+///
+/// On the wire a frame is a fixed preamble, a JSON header block and the raw body:
+/// `[version u8][type u8][header_len u32 BE][header JSON][body bytes]`.
+/// When compression was negotiated, the high bit of the type byte marks a zstd compressed
+/// body; see `compress_frame`. Body chunks are keyed by the request id, so many streams can share one connection.
+///
+/// A `WebSocketOpen` asks the client to open a WebSocket to the local service. It is
+/// answered with a `Response`: status 101 when the local handshake succeeded, after which
+/// both sides exchange `WebSocketMessage` frames until a close message or an `End` frame.
+/// Any other status is an ordinary HTTP response with body frames.
+///
+/// A `TcpOpen` announces a visitor connection on the client's TCP tunnel port. The byte
+/// streams in both directions are carried as `Data` frames, and an `End` frame half-closes
+/// the connection in the direction it was sent.
+///
+/// A `Datagram` carries one UDP datagram of a flow on the client's UDP tunnel port. The flow
+/// id and visitor address are echoed back on replies. An `End` frame closes an idle flow.
+///
+/// A `Cancel` tells the client that nobody is waiting for a request anymore, e.g. because
+/// the visitor disconnected or the server gave up waiting, so it can stop working on it.
+///
+/// The payload of `Data` and `WebSocketMessage` frames is flow controlled per stream: each
+/// side may have at most `STREAM_WINDOW` bytes of a stream in flight, and the receiver hands
+/// back credit with a `WindowUpdate` frame as it passes the bytes on. That way a stream whose
+/// reader is slow never holds up the other streams on the connection.
+///
+/// An `Error` reports a message that could not be handled, so a visitor waiting for the
+/// request it belongs to can be answered right away.
+///
+/// A `GoAway` announces that the server is shutting down. The connection stays up until the
+/// requests in flight have finished or the drain deadline has passed.
+///
+/// A `Trailers` frame carries the trailer fields of a request or response body, e.g. the
+/// `grpc-status` of a gRPC call. It follows the last `Data` frame and precedes the `End` frame.
+#[derive(Debug)]
+pub enum Frame {
+    Request(RequestHead),
+    Response(ResponseHead),
+    Data {
+        id: String,
+        chunk: Vec<u8>,
+    },
+    End {
+        id: String,
+    },
+    WebSocketOpen(RequestHead),
+    WebSocketMessage {
+        id: String,
+        kind: WebSocketMessageKind,
+        payload: Vec<u8>,
+    },
+    TcpOpen {
+        id: String,
+        peer_addr: String,
+    },
+    Datagram {
+        id: String,
+        peer_addr: String,
+        payload: Vec<u8>,
+    },
+    Cancel {
+        id: String,
+    },
+    WindowUpdate {
+        id: String,
+        increment: u32,
+    },
+    Error(TunnelError),
+    GoAway(GoAway),
+    Trailers {
+        id: String,
+        headers: Headers,
+    },
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    Header(serde_json::Error),
+    Decompress(std::io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "frame is truncated"),
+            FrameError::UnsupportedVersion(v) => write!(f, "unsupported frame version {}", v),
+            FrameError::UnknownType(t) => write!(f, "unknown frame type {}", t),
+            FrameError::Header(e) => write!(f, "invalid frame header: {}", e),
+            FrameError::Decompress(e) => write!(f, "failed to decompress frame body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<serde_json::Error> for FrameError {
+    fn from(e: serde_json::Error) -> Self {
+        FrameError::Header(e)
+    }
+}
+
+impl Frame {
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let (frame_type, header, body): (u8, Vec<u8>, &[u8]) = match self {
+            Frame::Request(head) => (FRAME_TYPE_REQUEST, serde_json::to_vec(head)?, &[]),
+            Frame::Response(head) => (FRAME_TYPE_RESPONSE, serde_json::to_vec(head)?, &[]),
+            Frame::Data { id, chunk } => (
+                FRAME_TYPE_DATA,
+                serde_json::to_vec(&StreamHeader { id: id.clone() })?,
+                chunk,
+            ),
+            Frame::End { id } => (
+                FRAME_TYPE_END,
+                serde_json::to_vec(&StreamHeader { id: id.clone() })?,
+                &[],
+            ),
+            Frame::WebSocketOpen(head) => {
+                (FRAME_TYPE_WEBSOCKET_OPEN, serde_json::to_vec(head)?, &[])
+            }
+            Frame::WebSocketMessage { id, kind, payload } => (
+                FRAME_TYPE_WEBSOCKET_MESSAGE,
+                serde_json::to_vec(&WebSocketMessageHeader {
+                    id: id.clone(),
+                    kind: kind.clone(),
+                })?,
+                payload,
+            ),
+            Frame::TcpOpen { id, peer_addr } => (
+                FRAME_TYPE_TCP_OPEN,
+                serde_json::to_vec(&PeerHeader {
+                    id: id.clone(),
+                    peer_addr: peer_addr.clone(),
+                })?,
+                &[],
+            ),
+            Frame::Datagram {
+                id,
+                peer_addr,
+                payload,
+            } => (
+                FRAME_TYPE_DATAGRAM,
+                serde_json::to_vec(&PeerHeader {
+                    id: id.clone(),
+                    peer_addr: peer_addr.clone(),
+                })?,
+                payload,
+            ),
+            Frame::Cancel { id } => (
+                FRAME_TYPE_CANCEL,
+                serde_json::to_vec(&StreamHeader { id: id.clone() })?,
+                &[],
+            ),
+            Frame::WindowUpdate { id, increment } => (
+                FRAME_TYPE_WINDOW_UPDATE,
+                serde_json::to_vec(&WindowUpdateHeader {
+                    id: id.clone(),
+                    increment: *increment,
+                })?,
+                &[],
+            ),
+            Frame::Error(error) => (FRAME_TYPE_ERROR, serde_json::to_vec(error)?, &[]),
+            Frame::GoAway(go_away) => (FRAME_TYPE_GO_AWAY, serde_json::to_vec(go_away)?, &[]),
+            Frame::Trailers { id, headers } => (
+                FRAME_TYPE_TRAILERS,
+                serde_json::to_vec(&TrailersHeader {
+                    id: id.clone(),
+                    headers: headers.clone(),
+                })?,
+                &[],
+            ),
+        };
+
+        let mut buf = Vec::with_capacity(PREAMBLE_LEN + header.len() + body.len());
+        buf.push(FRAME_VERSION);
+        buf.push(frame_type);
+        buf.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(body);
+        Ok(buf)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, FrameError> {
+        if data.len() < PREAMBLE_LEN {
+            return Err(FrameError::Truncated);
+        }
+        if data[0] != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(data[0]));
+        }
+
+        let frame_type = data[1] & !FLAG_COMPRESSED;
+        let header_len = u32::from_be_bytes([data[2], data[3], data[4], data[5]]) as usize;
+        let rest = &data[PREAMBLE_LEN..];
+        if rest.len() < header_len {
+            return Err(FrameError::Truncated);
+        }
+        let (header, body) = rest.split_at(header_len);
+        let body = if data[1] & FLAG_COMPRESSED != 0 {
+            Cow::Owned(
+                zstd::bulk::decompress(body, MAX_DECOMPRESSED_LEN)
+                    .map_err(FrameError::Decompress)?,
+            )
+        } else {
+            Cow::Borrowed(body)
+        };
+
+        match frame_type {
+            FRAME_TYPE_REQUEST => Ok(Frame::Request(serde_json::from_slice(header)?)),
+            FRAME_TYPE_RESPONSE => Ok(Frame::Response(serde_json::from_slice(header)?)),
+            FRAME_TYPE_DATA => {
+                let StreamHeader { id } = serde_json::from_slice(header)?;
+                Ok(Frame::Data {
+                    id,
+                    chunk: body.into_owned(),
+                })
+            }
+            FRAME_TYPE_END => {
+                let StreamHeader { id } = serde_json::from_slice(header)?;
+                Ok(Frame::End { id })
+            }
+            FRAME_TYPE_WEBSOCKET_OPEN => Ok(Frame::WebSocketOpen(serde_json::from_slice(header)?)),
+            FRAME_TYPE_WEBSOCKET_MESSAGE => {
+                let WebSocketMessageHeader { id, kind } = serde_json::from_slice(header)?;
+                Ok(Frame::WebSocketMessage {
+                    id,
+                    kind,
+                    payload: body.into_owned(),
+                })
+            }
+            FRAME_TYPE_TCP_OPEN => {
+                let PeerHeader { id, peer_addr } = serde_json::from_slice(header)?;
+                Ok(Frame::TcpOpen { id, peer_addr })
+            }
+            FRAME_TYPE_DATAGRAM => {
+                let PeerHeader { id, peer_addr } = serde_json::from_slice(header)?;
+                Ok(Frame::Datagram {
+                    id,
+                    peer_addr,
+                    payload: body.into_owned(),
+                })
+            }
+            FRAME_TYPE_CANCEL => {
+                let StreamHeader { id } = serde_json::from_slice(header)?;
+                Ok(Frame::Cancel { id })
+            }
+            FRAME_TYPE_WINDOW_UPDATE => {
+                let WindowUpdateHeader { id, increment } = serde_json::from_slice(header)?;
+                Ok(Frame::WindowUpdate { id, increment })
+            }
+            FRAME_TYPE_ERROR => Ok(Frame::Error(serde_json::from_slice(header)?)),
+            FRAME_TYPE_GO_AWAY => Ok(Frame::GoAway(serde_json::from_slice(header)?)),
+            FRAME_TYPE_TRAILERS => {
+                let TrailersHeader { id, headers } = serde_json::from_slice(header)?;
+                Ok(Frame::Trailers { id, headers })
+            }
+            other => Err(FrameError::UnknownType(other)),
+        }
+    }
+}
+
+/// The stream id in the header block of a frame that could not be decoded, if it has one, so
+/// the failure can still be matched to its request.
+pub fn frame_id(data: &[u8]) -> Option<String> {
+    let header_len = u32::from_be_bytes(data.get(2..PREAMBLE_LEN)?.try_into().ok()?) as usize;
+    let header = data.get(PREAMBLE_LEN..PREAMBLE_LEN.checked_add(header_len)?)?;
+    serde_json::from_slice::<StreamHeader>(header)
+        .ok()
+        .map(|header| header.id)
+}
+
+/// Credit a payload of `len` bytes takes from its stream's window. Payloads larger than the
+/// whole window, such as big WebSocket messages, take the whole window so they can still be sent.
+pub fn stream_credit(len: usize) -> u32 {
+    len.min(STREAM_WINDOW) as u32
+}
+
+/// Compresses the body of an encoded frame with zstd when it is at least
+/// `COMPRESSION_THRESHOLD` bytes long and actually shrinks. Otherwise the frame is returned
+/// unchanged, so the result can always be sent as is.
+pub fn compress_frame(frame: Vec<u8>) -> Vec<u8> {
+    if frame.len() < PREAMBLE_LEN || frame[1] & FLAG_COMPRESSED != 0 {
+        return frame;
+    }
+    let header_len = u32::from_be_bytes([frame[2], frame[3], frame[4], frame[5]]) as usize;
+    let body_start = PREAMBLE_LEN + header_len;
+    let Some(body) = frame.get(body_start..) else {
+        return frame;
+    };
+    if body.len() < COMPRESSION_THRESHOLD {
+        return frame;
+    }
+
+    match zstd::bulk::compress(body, COMPRESSION_LEVEL) {
+        Ok(compressed) if compressed.len() < body.len() => {
+            let mut buf = Vec::with_capacity(body_start + compressed.len());
+            buf.extend_from_slice(&frame[..body_start]);
+            buf[1] |= FLAG_COMPRESSED;
+            buf.extend_from_slice(&compressed);
+            buf
+        }
+        _ => frame,
+    }
+}
+
+/// Running totals of the frames one side of a connection has sent, before and after
+/// compression.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub frames: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    pub fn record(&mut self, uncompressed_len: usize, compressed_len: usize) {
+        self.frames += 1;
+        self.uncompressed_bytes += uncompressed_len as u64;
+        self.compressed_bytes += compressed_len as u64;
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ratio = if self.uncompressed_bytes == 0 {
+            100.0
+        } else {
+            self.compressed_bytes as f64 * 100.0 / self.uncompressed_bytes as f64
+        };
+        write!(
+            f,
+            "{} frames, {} bytes uncompressed, {} bytes on the wire ({:.1}%)",
+            self.frames, self.uncompressed_bytes, self.compressed_bytes, ratio
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_head(id: &str) -> RequestHead {
+        RequestHead {
+            id: id.to_string(),
+            method: "POST".to_string(),
+            path: "/upload".to_string(),
+            headers: vec![
+                ("set-cookie".to_string(), "a=1".to_string().into()),
+                ("content-type".to_string(), "text/plain".to_string().into()),
+                ("set-cookie".to_string(), "b=2".to_string().into()),
+                ("x-opaque".to_string(), HeaderBytes(vec![0xff, 0x00, 0x80])),
+            ],
+            query: Some("b=2&a=1&a=%20".to_string()),
+            has_body: true,
+            authority: Some("example.com".to_string()),
+            scheme: Some("https".to_string()),
+        }
+    }
+
+    fn round_trip(frame: &Frame) -> Frame {
+        Frame::decode(&frame.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn request_round_trips_with_repeated_and_binary_headers() {
+        let Frame::Request(head) = round_trip(&Frame::Request(request_head("r1"))) else {
+            panic!("expected a Request frame");
+        };
+        let expected = request_head("r1");
+        assert_eq!(head.id, expected.id);
+        assert_eq!(head.method, expected.method);
+        assert_eq!(head.path, expected.path);
+        assert_eq!(head.headers, expected.headers);
+        assert_eq!(head.query, expected.query);
+        assert!(head.has_body);
+        assert_eq!(head.authority, expected.authority);
+        assert_eq!(head.scheme, expected.scheme);
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let head = ResponseHead {
+            id: "r1".to_string(),
+            status: 404,
+            headers: Vec::new(),
+        };
+        assert!(matches!(
+            round_trip(&Frame::Response(head)),
+            Frame::Response(head) if head.id == "r1" && head.status == 404
+        ));
+    }
+
+    #[test]
+    fn stream_frames_round_trip() {
+        let chunk = vec![0, 1, 2, 255];
+        assert!(matches!(
+            round_trip(&Frame::Data { id: "d".to_string(), chunk: chunk.clone() }),
+            Frame::Data { id, chunk: decoded } if id == "d" && decoded == chunk
+        ));
+        assert!(matches!(
+            round_trip(&Frame::Data { id: "d".to_string(), chunk: Vec::new() }),
+            Frame::Data { chunk, .. } if chunk.is_empty()
+        ));
+        assert!(matches!(
+            round_trip(&Frame::End { id: "e".to_string() }),
+            Frame::End { id } if id == "e"
+        ));
+        assert!(matches!(
+            round_trip(&Frame::Cancel { id: "c".to_string() }),
+            Frame::Cancel { id } if id == "c"
+        ));
+        let trailers = vec![(
+            "grpc-status".to_string(),
+            HeaderBytes::from("0".to_string()),
+        )];
+        assert!(matches!(
+            round_trip(&Frame::Trailers { id: "t".to_string(), headers: trailers.clone() }),
+            Frame::Trailers { id, headers } if id == "t" && headers == trailers
+        ));
+        assert!(matches!(
+            round_trip(&Frame::WindowUpdate { id: "w".to_string(), increment: 65536 }),
+            Frame::WindowUpdate { id, increment } if id == "w" && increment == 65536
+        ));
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let encoded = Frame::End {
+            id: "e".to_string(),
+        }
+        .encode()
+        .unwrap();
+
+        assert!(matches!(Frame::decode(&[]), Err(FrameError::Truncated)));
+        assert!(matches!(
+            Frame::decode(&encoded[..4]),
+            Err(FrameError::Truncated)
+        ));
+        assert!(matches!(
+            Frame::decode(&encoded[..encoded.len() - 1]),
+            Err(FrameError::Truncated)
+        ));
+
+        let mut wrong_version = encoded.clone();
+        wrong_version[0] = FRAME_VERSION + 1;
+        assert!(matches!(
+            Frame::decode(&wrong_version),
+            Err(FrameError::UnsupportedVersion(v)) if v == FRAME_VERSION + 1
+        ));
+
+        let mut unknown_type = encoded.clone();
+        unknown_type[1] = 0x7f;
+        assert!(matches!(
+            Frame::decode(&unknown_type),
+            Err(FrameError::UnknownType(0x7f))
+        ));
+
+        let mut bad_header = encoded.clone();
+        bad_header[PREAMBLE_LEN] = b'[';
+        assert!(matches!(
+            Frame::decode(&bad_header),
+            Err(FrameError::Header(_))
+        ));
+    }
+
+    #[test]
+    fn websocket_frames_round_trip() {
+        let Frame::WebSocketOpen(head) = round_trip(&Frame::WebSocketOpen(request_head("ws")))
+        else {
+            panic!("expected a WebSocketOpen frame");
+        };
+        assert_eq!(head.id, "ws");
+        assert!(matches!(
+            round_trip(&Frame::WebSocketMessage {
+                id: "ws".to_string(),
+                kind: WebSocketMessageKind::Binary,
+                payload: vec![0, 255],
+            }),
+            Frame::WebSocketMessage { id, kind: WebSocketMessageKind::Binary, payload }
+                if id == "ws" && payload == vec![0, 255]
+        ));
+        assert!(matches!(
+            round_trip(&Frame::WebSocketMessage {
+                id: "ws".to_string(),
+                kind: WebSocketMessageKind::Close { code: Some(1000), reason: "bye".to_string() },
+                payload: Vec::new(),
+            }),
+            Frame::WebSocketMessage {
+                kind: WebSocketMessageKind::Close { code: Some(1000), reason },
+                ..
+            } if reason == "bye"
+        ));
+    }
+
+    #[test]
+    fn tcp_open_round_trips() {
+        let frame = Frame::TcpOpen {
+            id: "t".to_string(),
+            peer_addr: "203.0.113.7:50000".to_string(),
+        };
+        assert!(matches!(
+            round_trip(&frame),
+            Frame::TcpOpen { id, peer_addr } if id == "t" && peer_addr == "203.0.113.7:50000"
+        ));
+    }
+
+    #[test]
+    fn datagrams_round_trip() {
+        let frame = Frame::Datagram {
+            id: "u".to_string(),
+            peer_addr: "10.0.0.1:53".to_string(),
+            payload: vec![9; 3],
+        };
+        assert!(matches!(
+            round_trip(&frame),
+            Frame::Datagram { id, peer_addr, payload }
+                if id == "u" && peer_addr == "10.0.0.1:53" && payload == vec![9; 3]
+        ));
+    }
+
+    #[test]
+    fn capabilities_ignore_unknown_names_and_whitespace() {
+        let capabilities = Capabilities::parse("binary_frames, streaming,teleport,,udp");
+        assert!(capabilities.contains(Capability::BinaryFrames));
+        assert!(capabilities.contains(Capability::Streaming));
+        assert!(capabilities.contains(Capability::Udp));
+        assert!(!capabilities.contains(Capability::Compression));
+        assert_eq!(capabilities.to_string(), "binary_frames,streaming,udp");
+        assert_eq!(Capabilities::parse(""), Capabilities::default());
+    }
+
+    #[test]
+    fn capabilities_round_trip_and_intersect() {
+        let all: Capabilities = Capability::ALL.into_iter().collect();
+        assert_eq!(Capabilities::parse(&all.to_string()), all);
+
+        let ours = Capabilities::parse("binary_frames,compression,tcp");
+        let theirs = Capabilities::parse("tcp,binary_frames,websocket");
+        assert_eq!(ours.intersection(&theirs).to_string(), "binary_frames,tcp");
+    }
+
+    #[test]
+    fn large_bodies_are_compressed_and_decoded() {
+        let chunk = vec![b'a'; COMPRESSION_THRESHOLD * 4];
+        let encoded = Frame::Data {
+            id: "d".to_string(),
+            chunk: chunk.clone(),
+        }
+        .encode()
+        .unwrap();
+        let compressed = compress_frame(encoded.clone());
+        assert!(compressed.len() < encoded.len());
+        assert_ne!(compressed[1] & FLAG_COMPRESSED, 0);
+        assert!(matches!(
+            Frame::decode(&compressed),
+            Ok(Frame::Data { chunk: decoded, .. }) if decoded == chunk
+        ));
+        // Compressing twice leaves the frame alone.
+        assert_eq!(compress_frame(compressed.clone()), compressed);
+    }
+
+    #[test]
+    fn small_or_incompressible_bodies_are_left_alone() {
+        let small = Frame::Data {
+            id: "d".to_string(),
+            chunk: vec![b'a'; 16],
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(compress_frame(small.clone()), small);
+
+        // Pseudo-random bytes only grow when compressed.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..COMPRESSION_THRESHOLD * 4)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let incompressible = Frame::Data {
+            id: "d".to_string(),
+            chunk: noise,
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(compress_frame(incompressible.clone()), incompressible);
+
+        assert_eq!(compress_frame(vec![FRAME_VERSION]), vec![FRAME_VERSION]);
+    }
+
+    #[test]
+    fn corrupt_compressed_bodies_are_rejected() {
+        let mut encoded = Frame::Data {
+            id: "d".to_string(),
+            chunk: vec![1, 2, 3],
+        }
+        .encode()
+        .unwrap();
+        encoded[1] |= FLAG_COMPRESSED;
+        assert!(matches!(
+            Frame::decode(&encoded),
+            Err(FrameError::Decompress(_))
+        ));
+    }
+
+    #[test]
+    fn missing_query_stays_missing() {
+        let mut head = request_head("r1");
+        head.query = None;
+        let Frame::Request(head) = round_trip(&Frame::Request(head)) else {
+            panic!("expected a Request frame");
+        };
+        assert_eq!(head.query, None);
+    }
+
+    #[test]
+    fn header_bytes_are_text_when_possible_and_base64_otherwise() {
+        let text = HeaderBytes::from("a=1; Path=/".to_string());
+        assert_eq!(serde_json::to_string(&text).unwrap(), r#""a=1; Path=/""#);
+        let opaque = HeaderBytes(vec![0xff, 0x00, 0x80]);
+        assert_eq!(
+            serde_json::to_string(&opaque).unwrap(),
+            r#"{"base64":"/wCA"}"#
+        );
+        for value in [text, opaque] {
+            let json = serde_json::to_string(&value).unwrap();
+            assert_eq!(serde_json::from_str::<HeaderBytes>(&json).unwrap(), value);
+        }
+        assert!(serde_json::from_str::<HeaderBytes>(r#"{"base64":"not base64!"}"#).is_err());
+        assert_eq!(HeaderBytes(vec![b'a', 0xff]).to_string_lossy(), "a\u{fffd}");
+    }
+
+    #[test]
+    fn control_frames_round_trip() {
+        let error = TunnelError {
+            id: Some("r1".to_string()),
+            code: ErrorCode::UpstreamFailed,
+            message: "broken".to_string(),
+        };
+        assert!(matches!(
+            round_trip(&Frame::Error(error)),
+            Frame::Error(TunnelError { id: Some(id), code: ErrorCode::UpstreamFailed, message })
+                if id == "r1" && message == "broken"
+        ));
+        let go_away = GoAway {
+            message: "restarting".to_string(),
+            drain_timeout_secs: 30,
+        };
+        assert!(matches!(
+            round_trip(&Frame::GoAway(go_away)),
+            Frame::GoAway(GoAway {
+                drain_timeout_secs: 30,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_error_codes_decode_as_unknown() {
+        let header = br#"{"id":null,"code":"from_the_future","message":"?"}"#;
+        let mut data = vec![FRAME_VERSION, FRAME_TYPE_ERROR];
+        data.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        data.extend_from_slice(header);
+        assert!(matches!(
+            Frame::decode(&data),
+            Ok(Frame::Error(TunnelError {
+                code: ErrorCode::Unknown,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn frame_id_is_recovered_from_undecodable_frames() {
+        let mut encoded = Frame::Cancel {
+            id: "lost".to_string(),
+        }
+        .encode()
+        .unwrap();
+        encoded[1] = 0x7f;
+        assert!(Frame::decode(&encoded).is_err());
+        assert_eq!(frame_id(&encoded).as_deref(), Some("lost"));
+        assert_eq!(frame_id(&encoded[..3]), None);
+    }
+}
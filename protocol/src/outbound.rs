@@ -0,0 +1,319 @@
+//! Scheduling of outgoing frames, shared by both ends of the tunnel connection.
+
+use crate::{stream_credit, Frame, FrameError, STREAM_WINDOW};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, Semaphore};
+
+/// Datagrams queued per UDP flow before further ones are dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+/// The outgoing side of a tunnel connection, shared by every task that talks to the other end.
+/// `M` is the WebSocket message type of the connection.
+///
+/// Frames that steer the connection, such as heads, pings, cancellations and window updates,
+/// always go out first. Payload frames are queued per stream and written round-robin, one frame
+/// per stream at a time, so a large download cannot hold up a small response queued behind it.
+/// A stream may only queue `STREAM_WINDOW` payload bytes until the other end grants more credit.
+pub struct FrameSender<M> {
+    shared: Arc<Shared<M>>,
+}
+
+struct Shared<M> {
+    queues: Mutex<Queues<M>>,
+    ready: Notify,
+    /// Send credit per stream, in payload bytes.
+    credits: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+struct Queues<M> {
+    control: VecDeque<M>,
+    streams: HashMap<String, VecDeque<M>>,
+    /// Streams with queued frames, in the order they get their next turn.
+    turns: VecDeque<String>,
+    closed: bool,
+}
+
+impl<M> Default for Queues<M> {
+    fn default() -> Self {
+        Queues {
+            control: VecDeque::new(),
+            streams: HashMap::new(),
+            turns: VecDeque::new(),
+            closed: false,
+        }
+    }
+}
+
+/// Why a frame could not be queued.
+#[derive(Debug)]
+pub enum SendError {
+    /// The tunnel connection is closed.
+    Closed,
+    /// The stream was closed while the frame waited for credit.
+    StreamClosed,
+    /// Too many datagrams of the flow are already waiting.
+    QueueFull,
+    Encode(FrameError),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Closed => write!(f, "tunnel connection is closed"),
+            SendError::StreamClosed => write!(f, "stream is closed"),
+            SendError::QueueFull => write!(f, "stream queue is full"),
+            SendError::Encode(e) => write!(f, "failed to encode frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl<M> Clone for FrameSender<M> {
+    fn clone(&self) -> Self {
+        FrameSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<M> Default for FrameSender<M> {
+    fn default() -> Self {
+        FrameSender {
+            shared: Arc::new(Shared {
+                queues: Mutex::new(Queues::default()),
+                ready: Notify::new(),
+                credits: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+impl<M: From<Vec<u8>>> FrameSender<M> {
+    /// Queues a frame. Payload frames wait until their stream has enough credit.
+    pub async fn send_frame(&self, frame: Frame) -> Result<(), SendError> {
+        let message = M::from(frame.encode().map_err(SendError::Encode)?);
+        match frame {
+            Frame::Data { id, chunk: payload } | Frame::WebSocketMessage { id, payload, .. } => {
+                self.acquire_credit(&id, payload.len()).await?;
+                self.push_stream(id, message, usize::MAX)
+            }
+            // Queued behind the stream's payload so they cannot overtake it.
+            Frame::Trailers { id, .. } => self.push_stream(id, message, usize::MAX),
+            Frame::End { id } => {
+                self.close_stream(&id);
+                self.push_stream(id, message, usize::MAX)
+            }
+            // Datagrams may be lost anyway, so they are dropped instead of piling up.
+            Frame::Datagram { id, .. } => {
+                let _ = self.push_stream(id, message, MAX_QUEUED_DATAGRAMS);
+                Ok(())
+            }
+            _ => self.send_message(message),
+        }
+    }
+
+    /// Tells the other end that `len` payload bytes of a stream were passed on, so it may send
+    /// more.
+    pub fn acknowledge(&self, id: &str, len: usize) {
+        let increment = stream_credit(len);
+        if increment == 0 {
+            return;
+        }
+        let frame = Frame::WindowUpdate {
+            id: id.to_string(),
+            increment,
+        };
+        if let Ok(frame) = frame.encode() {
+            let _ = self.send_message(M::from(frame));
+        }
+    }
+}
+
+impl<M> FrameSender<M> {
+    /// Queues a message ahead of all payload frames.
+    pub fn send_message(&self, message: M) -> Result<(), SendError> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        if queues.closed {
+            return Err(SendError::Closed);
+        }
+        queues.control.push_back(message);
+        drop(queues);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Adds credit the other end granted with a `WindowUpdate` frame.
+    pub fn grant(&self, id: &str, increment: u32) {
+        if let Some(credit) = self.shared.credits.lock().unwrap().get(id) {
+            credit.add_permits(increment as usize);
+        }
+    }
+
+    /// Forgets a stream's credit. Tasks still waiting to send on it get an error.
+    pub fn close_stream(&self, id: &str) {
+        if let Some(credit) = self.shared.credits.lock().unwrap().remove(id) {
+            credit.close();
+        }
+    }
+
+    /// Drops everything still queued and fails all further sends.
+    pub fn close(&self) {
+        let mut queues = self.shared.queues.lock().unwrap();
+        *queues = Queues {
+            closed: true,
+            ..Queues::default()
+        };
+        drop(queues);
+        for (_, credit) in self.shared.credits.lock().unwrap().drain() {
+            credit.close();
+        }
+    }
+
+    /// Waits for the next message to write to the connection.
+    pub async fn next(&self) -> M {
+        loop {
+            if let Some(message) = self.pop() {
+                return message;
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+
+    fn pop(&self) -> Option<M> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        if let Some(message) = queues.control.pop_front() {
+            return Some(message);
+        }
+        let id = queues.turns.pop_front()?;
+        let queue = queues.streams.get_mut(&id)?;
+        let message = queue.pop_front();
+        if queue.is_empty() {
+            queues.streams.remove(&id);
+        } else {
+            queues.turns.push_back(id);
+        }
+        message
+    }
+
+    async fn acquire_credit(&self, id: &str, len: usize) -> Result<(), SendError> {
+        let credit = self
+            .shared
+            .credits
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(STREAM_WINDOW)))
+            .clone();
+        credit
+            .acquire_many(stream_credit(len))
+            .await
+            .map_err(|_| SendError::StreamClosed)?
+            .forget();
+        Ok(())
+    }
+
+    fn push_stream(&self, id: String, message: M, limit: usize) -> Result<(), SendError> {
+        let mut queues = self.shared.queues.lock().unwrap();
+        if queues.closed {
+            return Err(SendError::Closed);
+        }
+        let queue = queues.streams.entry(id.clone()).or_default();
+        if queue.len() >= limit {
+            return Err(SendError::QueueFull);
+        }
+        queue.push_back(message);
+        if queue.len() == 1 {
+            queues.turns.push_back(id);
+        }
+        drop(queues);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn next_frame(sender: &FrameSender<Vec<u8>>) -> Frame {
+        Frame::decode(&sender.next().await).unwrap()
+    }
+
+    fn data(id: &str, len: usize) -> Frame {
+        Frame::Data {
+            id: id.to_string(),
+            chunk: vec![0; len],
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stream_out_of_credit_does_not_starve_others() {
+        let sender = FrameSender::<Vec<u8>>::default();
+        sender.send_frame(data("big", STREAM_WINDOW)).await.unwrap();
+
+        let blocked = {
+            let sender = sender.clone();
+            tokio::spawn(async move { sender.send_frame(data("big", 1)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!blocked.is_finished());
+
+        sender.send_frame(data("small", 10)).await.unwrap();
+        assert!(matches!(next_frame(&sender).await, Frame::Data { id, .. } if id == "big"));
+        assert!(matches!(next_frame(&sender).await, Frame::Data { id, .. } if id == "small"));
+
+        sender.grant("big", 1);
+        blocked.await.unwrap().unwrap();
+        assert!(
+            matches!(next_frame(&sender).await, Frame::Data { id, chunk } if id == "big" && chunk.len() == 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn control_frames_overtake_data() {
+        let sender = FrameSender::<Vec<u8>>::default();
+        sender.send_frame(data("a", 10)).await.unwrap();
+        sender.send_frame(data("a", 10)).await.unwrap();
+        sender
+            .send_frame(Frame::Cancel {
+                id: "b".to_string(),
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(next_frame(&sender).await, Frame::Cancel { id } if id == "b"));
+        assert!(matches!(next_frame(&sender).await, Frame::Data { id, .. } if id == "a"));
+        assert!(matches!(next_frame(&sender).await, Frame::Data { id, .. } if id == "a"));
+    }
+
+    #[tokio::test]
+    async fn streams_take_turns() {
+        let sender = FrameSender::<Vec<u8>>::default();
+        for id in ["a", "a", "a", "b"] {
+            sender.send_frame(data(id, 10)).await.unwrap();
+        }
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            if let Frame::Data { id, .. } = next_frame(&sender).await {
+                order.push(id);
+            }
+        }
+        assert_eq!(order, ["a", "b", "a", "a"]);
+    }
+
+    #[tokio::test]
+    async fn granting_an_unknown_stream_is_harmless() {
+        let sender = FrameSender::<Vec<u8>>::default();
+        sender.grant("unknown", 1024);
+        assert!(sender.shared.credits.lock().unwrap().is_empty());
+
+        sender.send_frame(data("closed", 10)).await.unwrap();
+        sender.close_stream("closed");
+        sender.grant("closed", 1024);
+        assert!(sender.shared.credits.lock().unwrap().is_empty());
+    }
+}
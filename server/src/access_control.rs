@@ -1,4 +1,8 @@
-use crate::{models::ClientParams, AppState};
+// Handlers return axum responses directly as the error variant.
+#![allow(clippy::result_large_err)]
+
+use crate::tunnel::Tunnel;
+use crate::{domains, models::ClientParams, AppState};
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
@@ -12,7 +16,6 @@ use tracing::{error, warn};
 
 pub fn authenticate_client(
     auth_header: Option<TypedHeader<Authorization<axum_extra::headers::authorization::Bearer>>>,
-    params: &ClientParams,
     app_state: &Arc<AppState>,
 ) -> Result<(), impl IntoResponse> {
     let auth_header = if let Some(TypedHeader(auth_header)) = auth_header {
@@ -27,46 +30,101 @@ pub fn authenticate_client(
         return Err((StatusCode::FORBIDDEN, "Invalid token").into_response());
     }
 
-    if app_state.active_websockets.contains_key(&params.client_id) {
+    Ok(())
+}
+
+/// Rejects a client whose ID is still taken by another connection, unless both connections
+/// ask for the same load balancing, affinity and allow lists. The client then joins the tunnel.
+pub fn ensure_client_id_free(
+    params: &ClientParams,
+    app_state: &Arc<AppState>,
+) -> Result<(), impl IntoResponse> {
+    let Some(tunnel) = app_state.active_websockets.get(&params.client_id) else {
+        return Ok(());
+    };
+
+    match join_conflict(params, app_state, &tunnel) {
+        Some(reason) => Err((StatusCode::CONFLICT, reason).into_response()),
+        None => Ok(()),
+    }
+}
+
+/// Why the client cannot join `tunnel`, which already serves its ID. Does not look at
+/// `active_websockets`, so it can run while holding the tunnel's entry.
+pub fn join_conflict(
+    params: &ClientParams,
+    app_state: &AppState,
+    tunnel: &Tunnel,
+) -> Option<&'static str> {
+    if tunnel.balancing.is_none() || tunnel.balancing != params.balancing {
         error!(
             "Client ID '{}' already exists. Rejecting connection.",
             params.client_id
         );
-        return Err((StatusCode::CONFLICT, "Client ID already connected").into_response());
+        return Some("Client ID already connected");
     }
 
-    Ok(())
-}
+    if tunnel.affinity != params.affinity {
+        error!(
+            "Client '{}' wants to join the load balanced tunnel with a different affinity. Rejecting connection.",
+            params.client_id
+        );
+        return Some("Client ID is load balanced with a different affinity");
+    }
 
-pub fn add_allowed_ips(
-    app_state: &Arc<AppState>,
-    client_id: &str,
-    ips: Vec<String>,
-) -> Result<(), Response> {
-    app_state.allowed_ips.insert(client_id.to_string(), ips);
-    Ok(())
+    let same_allow_lists = app_state
+        .allowed_paths
+        .get(&params.client_id)
+        .is_some_and(|paths| *paths == params.allowed_paths)
+        && app_state
+            .allowed_ips
+            .get(&params.client_id)
+            .is_some_and(|ips| *ips == params.allowed_ips)
+        && app_state
+            .allowed_asns
+            .get(&params.client_id)
+            .is_some_and(|asns| *asns == params.allowed_asns)
+        && domains::custom_domains_of(app_state, &params.client_id) == params.domains;
+    if !same_allow_lists {
+        error!(
+            "Client '{}' wants to join the load balanced tunnel with different allow lists. Rejecting connection.",
+            params.client_id
+        );
+        return Some("Client ID is load balanced with different allow lists");
+    }
+
+    None
 }
 
-pub fn add_allowed_paths(
-    app_state: &Arc<AppState>,
-    client_id: &str,
-    paths: Vec<String>,
-) -> Result<(), impl IntoResponse> {
-    if paths.is_empty() {
+/// Rejects a client that does not list any paths to serve.
+pub fn ensure_paths_provided(params: &ClientParams) -> Result<(), impl IntoResponse> {
+    if params.allowed_paths.is_empty() {
         return Err((StatusCode::BAD_REQUEST, "No paths provided").into_response());
     }
 
-    app_state.allowed_paths.insert(client_id.to_string(), paths);
     Ok(())
 }
 
-pub fn add_allowed_asns(
-    app_state: &Arc<AppState>,
-    client_id: &str,
-    asns: Vec<u32>,
-) -> Result<(), Response> {
-    app_state.allowed_asns.insert(client_id.to_string(), asns);
-    Ok(())
+/// Registers the allow lists of a client ID's first connection. Later connections joining its
+/// load balanced tunnel must bring the same ones.
+pub fn add_allow_lists(app_state: &AppState, params: &ClientParams) {
+    let client_id = params.client_id.clone();
+    app_state
+        .allowed_paths
+        .insert(client_id.clone(), params.allowed_paths.clone());
+    app_state
+        .allowed_ips
+        .insert(client_id.clone(), params.allowed_ips.clone());
+    app_state
+        .allowed_asns
+        .insert(client_id, params.allowed_asns.clone());
+}
+
+/// Forgets the allow lists once the last connection of a client ID is gone.
+pub fn remove_allow_lists(app_state: &AppState, client_id: &str) {
+    app_state.allowed_paths.remove(client_id);
+    app_state.allowed_ips.remove(client_id);
+    app_state.allowed_asns.remove(client_id);
 }
 
 pub fn is_ip_allowed(
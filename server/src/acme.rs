@@ -0,0 +1,332 @@
+use crate::config::{AcmeChallenge, AcmeConfig};
+use crate::tls::{self, CertResolver};
+use crate::AppState;
+use axum::extract::Path as UrlPath;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{BoxError, Router};
+use dashmap::{DashMap, DashSet};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use rustls::pki_types::PrivateKeyDer;
+use rustls::sign::CertifiedKey;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+/// How often the certificates managed over ACME are checked for renewal.
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long a domain is left alone after an order for it failed, to stay clear of the CA's
+/// rate limits.
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(60 * 60);
+
+/// How long the CA gets to validate the challenges, and then to issue the certificate.
+const ORDER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Where the account is stored, inside the certificate directory. The leading dot keeps the
+/// certificate reloader from taking it for a host.
+const ACCOUNT_FILE: &str = ".acme/account.json";
+
+/// The ACME account, stored so that renewals and restarts do not register a new one each time.
+#[derive(Serialize, Deserialize)]
+struct StoredAccount {
+    directory_url: String,
+    credentials: AccountCredentials,
+}
+
+/// Gets certificates from an ACME CA and renews them, storing each one in `TLS_CERT_DIR` and
+/// handing it straight to the HTTPS listener.
+pub struct Acme {
+    config: AcmeConfig,
+    cert_dir: PathBuf,
+    resolver: Arc<CertResolver>,
+    account: OnceCell<Account>,
+    /// Key authorizations of pending HTTP-01 challenges, by token.
+    http_challenges: DashMap<String, String>,
+    /// Domains with an order in progress, so a domain is never ordered twice at once.
+    ordering: DashSet<String>,
+    /// When the last order for a domain failed.
+    failures: DashMap<String, Instant>,
+}
+
+impl Acme {
+    pub fn new(config: AcmeConfig, cert_dir: PathBuf, resolver: Arc<CertResolver>) -> Self {
+        Self {
+            config,
+            cert_dir,
+            resolver,
+            account: OnceCell::new(),
+            http_challenges: DashMap::new(),
+            ordering: DashSet::new(),
+            failures: DashMap::new(),
+        }
+    }
+
+    /// Whether `domain` is one of the server's own host names from `ACME_DOMAINS`.
+    pub fn is_server_domain(&self, domain: &str) -> bool {
+        self.config.domains.iter().any(|own| own == domain)
+    }
+
+    /// Gets a certificate for `domain` in the background, unless it already has one that is
+    /// not due for renewal.
+    pub fn request_certificate(self: &Arc<Self>, domain: String) {
+        let acme = self.clone();
+        tokio::spawn(async move { acme.ensure_certificate(&domain).await });
+    }
+
+    async fn ensure_certificate(&self, domain: &str) {
+        let cert_path = self.cert_dir.join(domain).join("cert.pem");
+        if !needs_renewal(&cert_path) {
+            return;
+        }
+        if self
+            .failures
+            .get(domain)
+            .is_some_and(|failed_at| failed_at.elapsed() < RETRY_AFTER_FAILURE)
+        {
+            return;
+        }
+        if !self.ordering.insert(domain.to_string()) {
+            return;
+        }
+
+        info!(
+            "Requesting a certificate for {} from {}",
+            domain, self.config.directory_url
+        );
+        match self.issue(domain).await {
+            Ok(()) => {
+                info!("Installed a new certificate for {}", domain);
+                self.failures.remove(domain);
+            }
+            Err(e) => {
+                error!("Failed to get a certificate for {}: {}", domain, e);
+                self.failures.insert(domain.to_string(), Instant::now());
+            }
+        }
+        self.ordering.remove(domain);
+    }
+
+    async fn account(&self) -> Result<&Account, BoxError> {
+        self.account
+            .get_or_try_init(|| self.load_or_create_account())
+            .await
+    }
+
+    async fn load_or_create_account(&self) -> Result<Account, BoxError> {
+        let builder = || match &self.config.ca_root {
+            Some(ca_root) => Account::builder_with_root(ca_root),
+            None => Account::builder(),
+        };
+
+        let path = self.cert_dir.join(ACCOUNT_FILE);
+        let stored = fs::read(&path)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<StoredAccount>(&json).ok())
+            .filter(|stored| stored.directory_url == self.config.directory_url);
+        if let Some(stored) = stored {
+            return Ok(builder()?.from_credentials(stored.credentials).await?);
+        }
+
+        let contact = self
+            .config
+            .contact_email
+            .as_ref()
+            .map(|email| format!("mailto:{}", email));
+        let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+        let new_account = NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        };
+        let (account, credentials) = builder()?
+            .create(&new_account, self.config.directory_url.clone(), None)
+            .await?;
+        info!("Registered ACME account {}", account.id());
+
+        let stored = StoredAccount {
+            directory_url: self.config.directory_url.clone(),
+            credentials,
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_private_file(&path, &serde_json::to_vec(&stored)?)?;
+        Ok(account)
+    }
+
+    /// Orders a certificate for `domain`, stores it and starts serving it.
+    async fn issue(&self, domain: &str) -> Result<(), BoxError> {
+        let account = self.account().await?;
+        let identifiers = [Identifier::Dns(domain.to_string())];
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+        let mut tokens = Vec::new();
+        let result = self.complete_order(&mut order, domain, &mut tokens).await;
+        for token in tokens {
+            self.http_challenges.remove(&token);
+        }
+        self.resolver.remove_challenge(domain);
+        let (cert_pem, key_pem) = result?;
+
+        let dir = self.cert_dir.join(domain);
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::create_dir_all(&dir)?;
+        // Each file is replaced in one step. Until both are, the reloader keeps the old pair.
+        write_private_file(&key_path, key_pem.as_bytes())?;
+        write_private_file(&cert_path, cert_pem.as_bytes())?;
+        let cert = tls::load_certified_key(&cert_path, &key_path)?;
+        self.resolver.insert(domain.to_string(), Arc::new(cert));
+        Ok(())
+    }
+
+    /// Answers the order's challenges and waits for the certificate. Returns the certificate
+    /// chain and the private key as PEM. The tokens of HTTP-01 challenges are added to
+    /// `tokens`, so the caller can clean them up however this ends.
+    async fn complete_order(
+        &self,
+        order: &mut Order,
+        domain: &str,
+        tokens: &mut Vec<String>,
+    ) -> Result<(String, String), BoxError> {
+        let challenge_type = match self.config.challenge {
+            AcmeChallenge::Http01 => ChallengeType::Http01,
+            AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+        };
+
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization?;
+            match authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => return Err(format!("authorization is {:?}", status).into()),
+            }
+            let mut challenge = authorization
+                .challenge(challenge_type.clone())
+                .ok_or_else(|| format!("the CA does not offer {:?}", challenge_type))?;
+            let key_authorization = challenge.key_authorization();
+            match self.config.challenge {
+                AcmeChallenge::Http01 => {
+                    tokens.push(challenge.token.clone());
+                    self.http_challenges.insert(
+                        challenge.token.clone(),
+                        key_authorization.as_str().to_string(),
+                    );
+                }
+                AcmeChallenge::TlsAlpn01 => {
+                    let cert = challenge_certificate(domain, key_authorization.digest().as_ref())?;
+                    self.resolver
+                        .insert_challenge(domain.to_string(), Arc::new(cert));
+                }
+            }
+            challenge.set_ready().await?;
+        }
+
+        let retries = RetryPolicy::new().timeout(ORDER_TIMEOUT);
+        let status = order.poll_ready(&retries).await?;
+        if status != OrderStatus::Ready {
+            return Err(format!("order is {:?}", status).into());
+        }
+        let key_pem = order.finalize().await?;
+        let cert_pem = order.poll_certificate(&retries).await?;
+        Ok((cert_pem, key_pem))
+    }
+}
+
+/// Whether the certificate at `cert_path` is missing, unreadable or in the last third of its
+/// lifetime, which is when Let's Encrypt recommends renewing.
+fn needs_renewal(cert_path: &Path) -> bool {
+    let Ok(pem) = fs::read(cert_path) else {
+        return true;
+    };
+    let Ok((_, pem)) = x509_parser::pem::parse_x509_pem(&pem) else {
+        return true;
+    };
+    let Ok(cert) = pem.parse_x509() else {
+        return true;
+    };
+    let validity = cert.validity();
+    let not_before = validity.not_before.timestamp();
+    let not_after = validity.not_after.timestamp();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as i64)
+        .unwrap_or_default();
+    now >= not_after - (not_after - not_before) / 3
+}
+
+/// The self-signed certificate that answers a TLS-ALPN-01 challenge (RFC 8737).
+fn challenge_certificate(domain: &str, digest: &[u8]) -> Result<CertifiedKey, BoxError> {
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest)];
+    let key_pair = rcgen::KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+    let key = PrivateKeyDer::Pkcs8(key_pair.serialize_der().into());
+    Ok(tls::certified_key(vec![cert.der().clone()], &key)?)
+}
+
+/// Replaces `path` in one step with a file only the server's user can read.
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+/// Answers the CA's HTTP-01 challenge requests. Served on the plain HTTP port, even when it
+/// redirects everything else to HTTPS.
+pub fn challenge_router(acme: Arc<Acme>) -> Router {
+    Router::new().route(
+        "/.well-known/acme-challenge/:token",
+        get(move |UrlPath(token): UrlPath<String>| async move {
+            match acme.http_challenges.get(&token) {
+                Some(key_authorization) => key_authorization.clone().into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }),
+    )
+}
+
+/// Gets the certificates that are still missing right away, then renews every certificate
+/// managed over ACME as it nears expiry: those of `ACME_DOMAINS` and the custom domains of
+/// connected clients.
+pub fn spawn_renewal_task(app_state: Arc<AppState>) {
+    let Some(acme) = app_state.acme.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RENEWAL_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut domains = acme.config.domains.clone();
+            domains.extend(
+                app_state
+                    .custom_domains
+                    .iter()
+                    .map(|entry| entry.key().clone()),
+            );
+            for domain in domains {
+                acme.ensure_certificate(&domain).await;
+            }
+        }
+    });
+}
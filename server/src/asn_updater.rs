@@ -45,7 +45,6 @@ async fn update_database(app_state: Arc<AppState>) -> Result<(), Box<dyn std::er
     info!("Downloading database checksum...");
     let checksum_data = download_file(&app_state, "tar.gz.sha256").await?;
     let expected_checksum = std::str::from_utf8(&checksum_data)?
-        .trim()
         .split_whitespace()
         .next()
         .unwrap_or("");
@@ -82,7 +81,7 @@ async fn update_database(app_state: Arc<AppState>) -> Result<(), Box<dyn std::er
 
     for entry in archive.entries()? {
         let mut entry = entry?;
-        if entry.path()?.extension().map_or(false, |ext| ext == "mmdb") {
+        if entry.path()?.extension().is_some_and(|ext| ext == "mmdb") {
             info!(path = ?entry.path()?.display(), "Found .mmdb file in archive.");
             let mut data = Vec::with_capacity(entry.size() as usize);
             entry.read_to_end(&mut data)?;
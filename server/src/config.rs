@@ -1,11 +1,56 @@
 use dotenvy::dotenv;
-use std::{env, path::PathBuf};
+use std::{env, ops::RangeInclusive, path::PathBuf, time::Duration};
 
 pub struct Config {
     pub secret_token: String,
     pub is_production: bool,
     pub asn_db_path: PathBuf,
     pub maxmind_license_key: String,
+    pub response_head_timeout: Duration,
+    pub tcp_port_range: Option<RangeInclusive<u16>>,
+    pub udp_port_range: Option<RangeInclusive<u16>>,
+    pub udp_flow_idle_timeout: Duration,
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+    pub drain_timeout: Duration,
+    pub trust_forwarded_for: bool,
+    /// Routes `<client_id>.<base_domain>` to the tunnel of that client id, next to path routing.
+    pub base_domain: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub acme: Option<AcmeConfig>,
+}
+
+/// Settings for the HTTPS and WSS listener, which is off unless `TLS_CERT_DIR` is set.
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// Holds a `<hostname>/cert.pem` and `<hostname>/key.pem` pair per host.
+    pub cert_dir: PathBuf,
+    pub port: u16,
+    /// Answers plain HTTP with a redirect to HTTPS.
+    pub redirect_http: bool,
+    /// Sends HSTS over HTTPS with this `max-age`, unless it is `None`.
+    pub hsts_max_age: Option<Duration>,
+}
+
+/// Settings for getting certificates from an ACME CA such as Let's Encrypt or Pebble, which
+/// is off unless `ACME_DIRECTORY_URL` is set.
+#[derive(Clone)]
+pub struct AcmeConfig {
+    pub directory_url: String,
+    pub contact_email: Option<String>,
+    /// The server's own host names. Custom domains of clients are added as they connect.
+    pub domains: Vec<String>,
+    /// Root certificate to trust for the directory, e.g. the one Pebble serves it with.
+    pub ca_root: Option<PathBuf>,
+    pub challenge: AcmeChallenge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    /// Answered on the plain HTTP port under `/.well-known/acme-challenge/`.
+    Http01,
+    /// Answered on the HTTPS port with a certificate for the `acme-tls/1` protocol.
+    TlsAlpn01,
 }
 
 impl Config {
@@ -20,11 +65,124 @@ impl Config {
             .unwrap_or(PathBuf::from("asn-test.mmdb"));
         let maxmind_license_key =
             std::env::var("MAXMIND_LICENSE_KEY").expect("MAXMIND_LICENSE_KEY must be set");
+        // Only bounds the wait for the response headers; bodies may stream for as long as they like.
+        let response_head_timeout = env::var("RESPONSE_HEAD_TIMEOUT_SECS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30));
+        // Ports handed out to clients that request a raw TCP tunnel, e.g. `40000-40100`.
+        // TCP tunnels are disabled when this is not set.
+        let tcp_port_range = env::var("TCP_PORT_RANGE")
+            .ok()
+            .map(|val| parse_port_range(&val).expect("TCP_PORT_RANGE must look like 40000-40100"));
+        // Same for UDP tunnels, which are disabled when this is not set.
+        let udp_port_range = env::var("UDP_PORT_RANGE")
+            .ok()
+            .map(|val| parse_port_range(&val).expect("UDP_PORT_RANGE must look like 41000-41100"));
+        // A UDP flow is forgotten once no datagram has passed in either direction for this long.
+        let udp_flow_idle_timeout = env::var("UDP_FLOW_IDLE_TIMEOUT_SECS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(60));
+        // Each tunnel is pinged this often, and closed when nothing comes back within
+        // `PONG_TIMEOUT_SECS`, so half-open connections are noticed quickly.
+        let ping_interval = env::var("PING_INTERVAL_SECS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(20));
+        let pong_timeout = env::var("PONG_TIMEOUT_SECS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(10));
+        // On SIGTERM, requests already in flight get this long to finish before the server exits.
+        let drain_timeout = env::var("DRAIN_TIMEOUT_SECS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30));
+        // Only a proxy in front of the server can vouch for `X-Forwarded-For`. Without one,
+        // visitors could pick the address the IP and ASN allow lists see.
+        let trust_forwarded_for = env::var("TRUST_FORWARDED_FOR")
+            .map(|val| val == "true")
+            .unwrap_or(false);
+        // Its wildcard record, e.g. `*.tunnel.example.com`, points at this server.
+        let base_domain = env::var("TUNNEL_BASE_DOMAIN")
+            .ok()
+            .map(|val| {
+                val.trim()
+                    .trim_start_matches("*.")
+                    .trim_matches('.')
+                    .to_ascii_lowercase()
+            })
+            .filter(|val| !val.is_empty());
+        let tls = env::var("TLS_CERT_DIR").ok().map(|cert_dir| TlsConfig {
+            cert_dir: PathBuf::from(cert_dir),
+            port: env::var("HTTPS_PORT")
+                .ok()
+                .map(|val| {
+                    val.parse::<u16>()
+                        .expect("HTTPS_PORT must be a port number")
+                })
+                .unwrap_or(3443),
+            redirect_http: env::var("HTTPS_REDIRECT")
+                .map(|val| val == "true")
+                .unwrap_or(false),
+            hsts_max_age: env::var("HSTS_MAX_AGE_SECS")
+                .ok()
+                .and_then(|val| val.parse::<u64>().ok())
+                .map(Duration::from_secs),
+        });
+        let acme = env::var("ACME_DIRECTORY_URL")
+            .ok()
+            .map(|directory_url| AcmeConfig {
+                directory_url,
+                contact_email: env::var("ACME_EMAIL").ok(),
+                domains: env::var("ACME_DOMAINS")
+                    .map(|val| {
+                        val.split(',')
+                            .map(|domain| domain.trim().to_ascii_lowercase())
+                            .filter(|domain| !domain.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                ca_root: env::var("ACME_CA_ROOT").ok().map(PathBuf::from),
+                challenge: match env::var("ACME_CHALLENGE").as_deref() {
+                    Ok("tls-alpn-01") => AcmeChallenge::TlsAlpn01,
+                    Ok("http-01") | Err(_) => AcmeChallenge::Http01,
+                    Ok(_) => panic!("ACME_CHALLENGE must be http-01 or tls-alpn-01"),
+                },
+            });
+        // Issued certificates are stored with the others and served by the HTTPS listener.
+        if acme.is_some() && tls.is_none() {
+            panic!("ACME_DIRECTORY_URL needs TLS_CERT_DIR to store the certificates in");
+        }
         Self {
             secret_token,
             is_production,
             asn_db_path,
             maxmind_license_key,
+            response_head_timeout,
+            tcp_port_range,
+            udp_port_range,
+            udp_flow_idle_timeout,
+            ping_interval,
+            pong_timeout,
+            drain_timeout,
+            trust_forwarded_for,
+            base_domain,
+            tls,
+            acme,
         }
     }
 }
+
+fn parse_port_range(val: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = val.split_once('-')?;
+    let start = start.trim().parse::<u16>().ok()?;
+    let end = end.trim().parse::<u16>().ok()?;
+    (start <= end).then_some(start..=end)
+}
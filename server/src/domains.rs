@@ -0,0 +1,283 @@
+// Handlers return axum responses directly as the error variant.
+#![allow(clippy::result_large_err)]
+
+use crate::AppState;
+use axum::extract::{Request, State};
+use axum::http::header::HOST;
+use axum::http::uri::{Authority, PathAndQuery};
+use axum::http::{StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Path prefix of ACME HTTP-01 challenges, which the server answers itself.
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Marks a request that reached its tunnel through the host name rather than a
+/// `/<client_id>` path prefix, so the visitor sees the local service's paths unchanged.
+#[derive(Clone, Copy, Debug)]
+pub struct HostRouted;
+
+/// Checks the custom domains a client asked for and returns them lowercased and sorted.
+/// Wildcards are not allowed, since their certificates cannot be validated over HTTP.
+pub fn parse_custom_domains(domains: &[String]) -> Result<Vec<String>, Response> {
+    let mut parsed = Vec::new();
+    for domain in domains
+        .iter()
+        .map(|domain| domain.trim().to_ascii_lowercase())
+    {
+        if domain.is_empty() {
+            continue;
+        }
+        if !is_valid_domain(&domain) {
+            error!("Rejected invalid custom domain '{}'", domain);
+            return Err((StatusCode::BAD_REQUEST, "Invalid custom domain").into_response());
+        }
+        parsed.push(domain);
+    }
+    parsed.sort();
+    parsed.dedup();
+    Ok(parsed)
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// The host name `client_id` is reached at under `TUNNEL_BASE_DOMAIN`. Client ids that are not
+/// a lowercase DNS label are only reachable by path.
+pub fn tunnel_host(app_state: &AppState, client_id: &str) -> Option<String> {
+    let base_domain = app_state.base_domain.as_ref()?;
+    let host = format!("{}.{}", client_id, base_domain);
+    let is_label = !client_id.contains('.') && client_id == client_id.to_ascii_lowercase();
+    (is_label && is_valid_domain(&host)).then_some(host)
+}
+
+/// The client id in a `<client_id>.<base_domain>` host name.
+fn client_id_from_subdomain<'a>(host: &'a str, base_domain: &str) -> Option<&'a str> {
+    host.strip_suffix(base_domain)?
+        .strip_suffix('.')
+        .filter(|label| !label.is_empty() && !label.contains('.'))
+}
+
+/// Whether `domain` belongs to the server: one of its ACME host names, or the tunnel base
+/// domain and the subdomains under it.
+fn is_reserved(app_state: &AppState, domain: &str) -> bool {
+    let server_domain = app_state
+        .acme
+        .as_ref()
+        .is_some_and(|acme| acme.is_server_domain(domain));
+    let under_base_domain = app_state.base_domain.as_ref().is_some_and(|base_domain| {
+        domain == base_domain || client_id_from_subdomain(domain, base_domain).is_some()
+    });
+    server_domain || under_base_domain
+}
+
+/// The custom domains currently routed to `client_id`, sorted.
+pub fn custom_domains_of(app_state: &AppState, client_id: &str) -> Vec<String> {
+    let mut domains: Vec<String> = app_state
+        .custom_domains
+        .iter()
+        .filter(|entry| entry.value() == client_id)
+        .map(|entry| entry.key().clone())
+        .collect();
+    domains.sort();
+    domains
+}
+
+/// Rejects a handshake asking for a custom domain that is already taken. The domains are only
+/// claimed once the connection is up, see `claim_custom_domains`.
+pub fn ensure_domains_free(
+    app_state: &AppState,
+    client_id: &str,
+    domains: &[String],
+) -> Result<(), Response> {
+    let taken = domains.iter().find(|domain| {
+        is_reserved(app_state, domain)
+            || app_state
+                .custom_domains
+                .get(*domain)
+                .is_some_and(|owner| owner.as_str() != client_id)
+    });
+    if let Some(domain) = taken {
+        error!(
+            "Client '{}' asked for custom domain '{}', which is taken",
+            client_id, domain
+        );
+        return Err((StatusCode::CONFLICT, "Custom domain is already in use").into_response());
+    }
+    Ok(())
+}
+
+/// Routes `domains` to `client_id`. Fails without claiming any of them if one belongs to
+/// another client or belongs to the server.
+pub fn claim_custom_domains(
+    app_state: &AppState,
+    client_id: &str,
+    domains: &[String],
+) -> Result<(), &'static str> {
+    let mut claimed = Vec::new();
+    for domain in domains {
+        let owner = app_state
+            .custom_domains
+            .entry(domain.clone())
+            .or_insert_with(|| {
+                claimed.push(domain.clone());
+                client_id.to_string()
+            })
+            .clone();
+        if owner != client_id || is_reserved(app_state, domain) {
+            error!(
+                "Client '{}' asked for custom domain '{}', which is taken",
+                client_id, domain
+            );
+            for domain in claimed {
+                app_state.custom_domains.remove(&domain);
+            }
+            return Err("Custom domain is already in use");
+        }
+    }
+    if !domains.is_empty() {
+        info!(
+            "Routing custom domains {} to client_id '{}'",
+            domains.join(", "),
+            client_id
+        );
+    }
+    Ok(())
+}
+
+pub fn release_custom_domains(app_state: &AppState, client_id: &str) {
+    app_state
+        .custom_domains
+        .retain(|_, owner| owner.as_str() != client_id);
+}
+
+/// Sends requests for a custom domain or a `<client_id>.<base_domain>` subdomain to the tunnel
+/// they belong to, by putting the tunnel's `/<client_id>` prefix in front of the path before
+/// the request is routed. Other hosts, such as the base domain itself, keep path routing.
+pub async fn route_by_host(
+    State(app_state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .or_else(|| request.uri().authority().cloned());
+    let client_id = host.and_then(|host| {
+        let host = host.host().to_ascii_lowercase();
+        app_state
+            .custom_domains
+            .get(&host)
+            .map(|client_id| client_id.clone())
+            .or_else(|| {
+                let base_domain = app_state.base_domain.as_ref()?;
+                client_id_from_subdomain(&host, base_domain).map(str::to_string)
+            })
+    });
+    let Some(client_id) = client_id else {
+        return next.run(request).await;
+    };
+    if app_state.acme.is_some() && request.uri().path().starts_with(ACME_CHALLENGE_PREFIX) {
+        return next.run(request).await;
+    }
+
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(PathAndQuery::as_str)
+        .unwrap_or("/");
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = format!("/{}{}", client_id, path_and_query).parse().ok();
+    match Uri::from_parts(parts) {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid path").into_response(),
+    }
+    request.extensions_mut().insert(HostRouted);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(domains: &[&str]) -> Option<Vec<String>> {
+        let domains: Vec<String> = domains.iter().map(|domain| domain.to_string()).collect();
+        parse_custom_domains(&domains).ok()
+    }
+
+    #[test]
+    fn custom_domains_are_normalized() {
+        assert_eq!(
+            parse(&[
+                " Shop.Example.COM ",
+                "",
+                "api.example.com",
+                "shop.example.com"
+            ]),
+            Some(vec![
+                "api.example.com".to_string(),
+                "shop.example.com".to_string()
+            ])
+        );
+        assert_eq!(parse(&[]), Some(Vec::new()));
+        assert_eq!(parse(&["  "]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn invalid_custom_domains_are_rejected() {
+        for domain in [
+            "localhost",
+            "*.example.com",
+            "shop..example.com",
+            ".example.com",
+            "example.com.",
+            "-shop.example.com",
+            "shop-.example.com",
+            "shop_1.example.com",
+            "shop.example.com:8443",
+            "xn--bcher-kva.example.com/path",
+        ] {
+            assert_eq!(parse(&["ok.example.com", domain]), None, "{}", domain);
+        }
+        let long_label = format!("{}.example.com", "a".repeat(64));
+        assert_eq!(parse(&[long_label.as_str()]), None);
+        let long_domain = format!("{}.com", vec!["a".repeat(60); 5].join("."));
+        assert_eq!(parse(&[long_domain.as_str()]), None);
+    }
+
+    #[test]
+    fn client_ids_are_read_from_a_single_subdomain_label() {
+        let base = "tunnel.example.com";
+        assert_eq!(
+            client_id_from_subdomain("demo.tunnel.example.com", base),
+            Some("demo")
+        );
+        assert_eq!(client_id_from_subdomain("tunnel.example.com", base), None);
+        assert_eq!(client_id_from_subdomain(".tunnel.example.com", base), None);
+        assert_eq!(
+            client_id_from_subdomain("a.b.tunnel.example.com", base),
+            None
+        );
+        assert_eq!(
+            client_id_from_subdomain("demotunnel.example.com", base),
+            None
+        );
+        assert_eq!(
+            client_id_from_subdomain("demo.other.example.com", base),
+            None
+        );
+    }
+}
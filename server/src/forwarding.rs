@@ -1,135 +1,554 @@
+use crate::domains::HostRouted;
 use crate::models::TunneledRequest;
-use crate::{access_control, AppState};
-use axum::extract::ws::Message;
+use crate::outbound::FrameSender;
+use crate::protocol::{
+    self, Capability, Frame, HeaderBytes, Headers, RequestHead, ResponseHead, MAX_CHUNK_SIZE,
+};
+use crate::tunnel::{Affinity, Member};
+use crate::{access_control, grpc, websocket_tunnel, AppState};
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use axum::extract::RawQuery;
 use axum::extract::{ConnectInfo, State};
-use axum::extract::{Path, Query};
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::http::header::{CONTENT_TYPE, COOKIE, HOST, SET_COOKIE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
 use axum::response::{IntoResponse, Response};
-use base64::engine::general_purpose;
-use base64::Engine;
-use std::collections::HashMap;
+use axum::{BoxError, Extension};
+use http_body::Body as _;
+use http_body_util::{BodyExt, LengthLimitError};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::sync::oneshot;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
+/// Largest request body buffered for clients on the JSON text protocol, the same as axum's
+/// default body limit.
+const BUFFERED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Cookie that keeps a visitor on one connection of a tunnel with cookie affinity.
+const AFFINITY_COOKIE: &str = "yats_affinity";
+
+/// A response head from the client together with a body that is fed as frames arrive.
+pub type TunnelResponse = (ResponseHead, Body);
+
+/// A visitor waiting for the response head of a request sent over the tunnel connection
+/// `connection_id`. Dropping the sender answers the visitor with a 502 right away, e.g. when
+/// that connection goes down.
+pub struct PendingResponse {
+    pub connection_id: String,
+    pub sender: oneshot::Sender<TunnelResponse>,
+}
+
+/// The tunnel connection picked to serve a request.
+pub struct Route {
+    pub member: Member,
+    /// Pins the visitor to `member`. Set on the response when the tunnel has cookie affinity
+    /// and the visitor was not pinned to that connection yet.
+    pub affinity_cookie: Option<HeaderValue>,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_forwarding_request(
     app_state: Arc<AppState>,
     client_id: String,
     method: Method,
-    headers: HeaderMap,
-    body: bytes::Bytes,
+    mut headers: HeaderMap,
+    body: Body,
     forward_path: String,
-    query_params: HashMap<String, String>,
+    query: Option<String>,
     remote_ip: IpAddr,
+    cookie_path: String,
 ) -> Response {
     info!(
-        "Forwarding request for client_id: {}, path: {}, method: {}, query_params: {:?}",
+        "Forwarding request for client_id: {}, path: {}, method: {}, query: {:?}",
         client_id,
         forward_path,
         method.as_str(),
-        query_params
+        query
     );
 
-    if let Err(response) = access_control::is_ip_allowed(&app_state, &client_id, remote_ip) {
+    if let Err(response) = check_access(&app_state, &client_id, &forward_path, remote_ip).await {
         return response;
     }
 
-    if let Err(response) = access_control::is_path_allowed(&app_state, &client_id, &forward_path) {
-        return response.into_response();
+    let Some(Route {
+        member,
+        affinity_cookie,
+    }) = route_request(
+        &app_state,
+        &client_id,
+        &headers,
+        remote_ip,
+        &cookie_path,
+        None,
+    )
+    else {
+        return (StatusCode::NOT_FOUND, "Client not connected").into_response();
+    };
+    strip_affinity_cookie(&mut headers);
+    let ws_sender = &member.sender;
+    let in_flight = member.start_request();
+
+    let request_id = Uuid::new_v4().to_string();
+    let head = RequestHead {
+        id: request_id.clone(),
+        method: method.to_string(),
+        path: forward_path,
+        headers: headers_to_list(&headers),
+        query,
+        has_body: body.size_hint().exact() != Some(0),
+        authority: request_authority(&headers),
+        scheme: Some(request_scheme(&headers)),
+    };
+    // Bodies in binary frames are always streamed, so the client has to support both.
+    let use_binary_frames = member.capabilities.contains(Capability::BinaryFrames)
+        && member.capabilities.contains(Capability::Streaming);
+
+    let supports_cancel = member.capabilities.contains(Capability::Cancel);
+    let supports_trailers = member.capabilities.contains(Capability::Trailers);
+
+    // Dropped together with this future if the visitor disconnects while we wait.
+    let (pending, rx) = PendingRequest::new(
+        &app_state,
+        &member.connection_id,
+        ws_sender,
+        &request_id,
+        supports_cancel,
+    );
+
+    let sent = if use_binary_frames {
+        send_streamed_request(ws_sender, head, body, supports_cancel, supports_trailers).await
+    } else {
+        send_buffered_request(ws_sender, head, body).await
+    };
+    if let Err(e) = sent {
+        if is_body_too_large(&*e) {
+            error!(
+                "Request body for client_id {} is larger than {} bytes",
+                client_id, BUFFERED_BODY_LIMIT
+            );
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response();
+        }
+        error!("Failed to forward request to websocket: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to forward request to client",
+        )
+            .into_response();
     }
 
-    if let Err(response) = access_control::is_asn_allowed(&app_state, &client_id, remote_ip).await {
-        return response.into_response();
+    // The timeout only covers the response head. Once it has arrived, the body keeps streaming
+    // to the visitor for as long as the local service writes, which keeps SSE and NDJSON working.
+    // A gRPC server may hold back the head of a streaming call until it has something to say,
+    // so gRPC calls with a deadline wait as long as it allows.
+    let head_timeout = grpc::is_grpc_request(&headers)
+        .then(|| grpc::grpc_timeout(&headers))
+        .flatten()
+        .unwrap_or(app_state.response_head_timeout);
+    let response_head = tokio::time::timeout(head_timeout, rx).await;
+    match response_head {
+        Ok(Ok((response_head, response_body))) => {
+            pending.complete();
+            if is_gateway_error(response_head.status) {
+                member.record_failure();
+            } else {
+                member.record_success();
+            }
+            let mut response = build_response(response_head, in_flight.hold_for(response_body));
+            if let Some(cookie) = affinity_cookie {
+                response.headers_mut().append(SET_COOKIE, cookie);
+            }
+            response
+        }
+        Ok(Err(_)) => {
+            info!(
+                "Tunnel closed before the response to request ID {} arrived",
+                request_id
+            );
+            member.record_failure();
+            (StatusCode::BAD_GATEWAY, "Client disconnected").into_response()
+        }
+        Err(_) => {
+            info!(
+                "Timed out waiting for the response to request ID {}",
+                request_id
+            );
+            member.record_failure();
+            (StatusCode::GATEWAY_TIMEOUT, "Request to client timed out").into_response()
+        }
     }
+}
 
-    if let Some(ws_sender) = app_state.active_websockets.get(&client_id) {
-        let headers_map: HashMap<String, String> = headers
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
-            .collect();
+/// A request that is waiting for its response head. Unless it is completed, dropping it
+/// forgets the request and, if the client supports it, sends a `Cancel` frame so the client
+/// stops working on it. That covers both timeouts and visitors that disconnect, since axum
+/// drops the handler future in that case.
+pub struct PendingRequest {
+    app_state: Arc<AppState>,
+    ws_sender: FrameSender,
+    request_id: String,
+    supports_cancel: bool,
+    completed: bool,
+}
 
-        let request_id = Uuid::new_v4().to_string();
-        let tunneled_request = TunneledRequest {
-            id: request_id.clone(),
-            method: method.to_string(),
-            path: forward_path,
-            headers: headers_map,
-            query_params,
-            body: general_purpose::STANDARD.encode(body),
+impl PendingRequest {
+    /// Registers the request as waiting and returns the receiver its response arrives on.
+    pub fn new(
+        app_state: &Arc<AppState>,
+        connection_id: &str,
+        ws_sender: &FrameSender,
+        request_id: &str,
+        supports_cancel: bool,
+    ) -> (Self, oneshot::Receiver<TunnelResponse>) {
+        let (sender, receiver) = oneshot::channel();
+        app_state.pending_responses.insert(
+            request_id.to_string(),
+            PendingResponse {
+                connection_id: connection_id.to_string(),
+                sender,
+            },
+        );
+        let pending = Self {
+            app_state: app_state.clone(),
+            ws_sender: ws_sender.clone(),
+            request_id: request_id.to_string(),
+            supports_cancel,
+            completed: false,
         };
+        (pending, receiver)
+    }
 
-        let (tx, rx) = oneshot::channel();
-        app_state.pending_responses.insert(request_id.clone(), tx);
-
-        match serde_json::to_string(&tunneled_request) {
-            Ok(json_payload) => {
-                if let Err(e) = ws_sender.send(Message::Text(json_payload)).await {
-                    error!("Failed to send request to websocket: {}", e);
-                    app_state.pending_responses.remove(&request_id);
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Failed to forward request to client",
-                    )
-                        .into_response();
-                }
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
 
-                match tokio::time::timeout(tokio::time::Duration::from_secs(30), rx).await {
-                    Ok(Ok(response)) => {
-                        let mut builder = axum::response::Response::builder().status(
-                            StatusCode::from_u16(response.status)
-                                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
-                        );
-
-                        for (key, value) in response.headers {
-                            builder = builder.header(key, value);
-                        }
-
-                        let body = response
-                            .body
-                            .and_then(|b| general_purpose::STANDARD.decode(b).ok());
-                        builder
-                            .body(axum::body::Body::from(body.unwrap_or_default()))
-                            .unwrap_or_else(|_| {
-                                (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    "Failed to build response",
-                                )
-                                    .into_response()
-                            })
-                    }
-                    Ok(Err(_)) | Err(_) => {
-                        app_state.pending_responses.remove(&request_id);
-                        (StatusCode::GATEWAY_TIMEOUT, "Request to client timed out").into_response()
-                    }
-                }
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        self.app_state.pending_responses.remove(&self.request_id);
+        self.app_state.websocket_streams.remove(&self.request_id);
+        // An upload still waiting for credit would otherwise wait forever.
+        self.ws_sender.close_stream(&self.request_id);
+        if !self.supports_cancel {
+            return;
+        }
+
+        info!("Cancelling request ID {} on the client", self.request_id);
+        let frame = Frame::Cancel {
+            id: self.request_id.clone(),
+        };
+        if let Ok(frame) = frame.encode() {
+            let _ = self.ws_sender.send_message(Message::Binary(frame));
+        }
+    }
+}
+
+/// Runs the IP, path and ASN checks that every forwarded request has to pass.
+pub async fn check_access(
+    app_state: &Arc<AppState>,
+    client_id: &str,
+    forward_path: &str,
+    remote_ip: IpAddr,
+) -> Result<(), Response> {
+    access_control::is_ip_allowed(app_state, client_id, remote_ip)?;
+    access_control::is_path_allowed(app_state, client_id, forward_path)
+        .map_err(IntoResponse::into_response)?;
+    access_control::is_asn_allowed(app_state, client_id, remote_ip)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Ok(())
+}
+
+/// Picks the tunnel connection of `client_id` that serves the next request. With affinity,
+/// the visitor keeps landing on the same connection for as long as it is healthy. An affinity
+/// cookie is scoped to `cookie_path`, where the tunnel is reached. Only connections that
+/// support `required` are picked.
+pub fn route_request(
+    app_state: &AppState,
+    client_id: &str,
+    headers: &HeaderMap,
+    remote_ip: IpAddr,
+    cookie_path: &str,
+    required: Option<Capability>,
+) -> Option<Route> {
+    let mut tunnel = app_state.active_websockets.get_mut(client_id)?;
+    let key = match &tunnel.affinity {
+        None => None,
+        Some(Affinity::Cookie) => affinity_cookie(headers),
+        Some(Affinity::Header(name)) => headers
+            .get(name.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        Some(Affinity::Ip) => Some(remote_ip.to_string()),
+    };
+    let member = tunnel.pick_for(key.as_deref(), required)?;
+
+    let pinned = key.as_deref() == Some(member.connection_id.as_str());
+    let affinity_cookie = if tunnel.affinity == Some(Affinity::Cookie) && !pinned {
+        HeaderValue::from_str(&format!(
+            "{}={}; Path={}; HttpOnly; SameSite=Lax",
+            AFFINITY_COOKIE, member.connection_id, cookie_path
+        ))
+        .ok()
+    } else {
+        None
+    };
+    Some(Route {
+        member,
+        affinity_cookie,
+    })
+}
+
+/// The connection id in the visitor's affinity cookie, if it sent one.
+fn affinity_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(AFFINITY_COOKIE)?.strip_prefix('='))
+        .map(str::to_string)
+}
+
+/// Removes the affinity cookie from the request, since it only means something to the server.
+pub fn strip_affinity_cookie(headers: &mut HeaderMap) {
+    if affinity_cookie(headers).is_none() {
+        return;
+    }
+    let cookies: Vec<HeaderValue> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| {
+            let Ok(value) = value.to_str() else {
+                return Some(value.clone());
+            };
+            let kept: Vec<&str> = value
+                .split(';')
+                .map(str::trim)
+                .filter(|pair| {
+                    pair.strip_prefix(AFFINITY_COOKIE)
+                        .is_none_or(|rest| !rest.starts_with('='))
+                })
+                .collect();
+            if kept.is_empty() {
+                None
+            } else {
+                HeaderValue::from_str(&kept.join("; ")).ok()
             }
+        })
+        .collect();
+    headers.remove(COOKIE);
+    for cookie in cookies {
+        headers.append(COOKIE, cookie);
+    }
+}
+
+/// Whether a response means the request did not get through to a working local service, in
+/// which case the tunnel connection that served it counts as failing.
+pub fn is_gateway_error(status: u16) -> bool {
+    matches!(status, 502..=504)
+}
+
+/// The address of the visitor. `X-Forwarded-For` only counts with `TRUST_FORWARDED_FOR`, and
+/// then only its last entry, which the proxy in front of the server appended.
+fn visitor_ip(headers: &HeaderMap, remote_addr: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .next_back()
+        .filter(|_| trust_forwarded_for)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.rsplit(',').next())
+        .and_then(|s| s.trim().parse::<IpAddr>().ok())
+        .unwrap_or(remote_addr.ip())
+}
+
+/// The host the visitor asked for. HTTP/2 requests carry it as `:authority`, which
+/// `forward_handler` turns into a `Host` header like HTTP/1.1 has.
+pub fn request_authority(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// The scheme the visitor used, as reported by a proxy in front of the server, or plain `http`.
+pub fn request_scheme(headers: &HeaderMap) -> String {
+    headers
+        .get("X-Forwarded-Proto")
+        .and_then(|value| value.to_str().ok())
+        .filter(|scheme| *scheme == "http" || *scheme == "https")
+        .unwrap_or("http")
+        .to_string()
+}
+
+/// Converts request headers for the tunnel, keeping repeated fields such as `Cookie`.
+pub fn headers_to_list(headers: &HeaderMap) -> Headers {
+    headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), HeaderBytes(v.as_bytes().to_vec())))
+        .collect()
+}
+
+/// Appends tunnelled header fields to `header_map`, keeping repeated fields such as
+/// `Set-Cookie`. Fields that are not valid HTTP are dropped and counted.
+pub fn append_headers(header_map: &mut HeaderMap, headers: Headers, request_id: &str) {
+    for (key, value) in headers {
+        match (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_bytes(&value.0),
+        ) {
+            (Ok(name), Ok(value)) => {
+                header_map.append(name, value);
+            }
+            _ => {
+                let dropped = protocol::record_dropped_header();
+                warn!(
+                    "Dropped invalid header '{}' for request ID {} ({} dropped so far)",
+                    key, request_id, dropped
+                );
+            }
+        }
+    }
+}
+
+/// Builds the visitor's response from the head and body the client sent back.
+pub fn build_response(head: ResponseHead, body: Body) -> Response {
+    let mut response = Response::new(body);
+    *response.status_mut() =
+        StatusCode::from_u16(head.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    append_headers(response.headers_mut(), head.headers, &head.id);
+    response
+}
+
+/// Sends the request head as a binary frame and streams the body after it in the background.
+async fn send_streamed_request(
+    ws_sender: &FrameSender,
+    head: RequestHead,
+    body: Body,
+    supports_cancel: bool,
+    supports_trailers: bool,
+) -> Result<(), BoxError> {
+    let request_id = head.id.clone();
+    let has_body = head.has_body;
+    ws_sender.send_frame(Frame::Request(head)).await?;
+
+    if has_body {
+        tokio::spawn(stream_request_body(
+            ws_sender.clone(),
+            request_id,
+            body,
+            supports_cancel,
+            supports_trailers,
+        ));
+    }
+    Ok(())
+}
+
+/// Forwards the visitor's request body as `Data` frames and its trailers, if any, as a
+/// `Trailers` frame, followed by an `End` frame. If the visitor aborts the upload, the request
+/// is cancelled where supported and the stream is left unfinished otherwise, so the local
+/// service never sees a truncated body as a complete one.
+async fn stream_request_body(
+    ws_sender: FrameSender,
+    request_id: String,
+    mut body: Body,
+    supports_cancel: bool,
+    supports_trailers: bool,
+) {
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
             Err(e) => {
-                error!("Failed to serialize request: {}", e);
-                app_state.pending_responses.remove(&request_id);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to serialize request",
-                )
-                    .into_response()
+                error!("Failed to read request body for ID {}: {}", request_id, e);
+                if supports_cancel {
+                    info!("Cancelling request ID {} on the client", request_id);
+                    let _ = ws_sender.send_frame(Frame::Cancel { id: request_id }).await;
+                }
+                return;
+            }
+        };
+        let chunk = match frame.into_data() {
+            Ok(chunk) => chunk,
+            Err(frame) => {
+                let Ok(trailers) = frame.into_trailers() else {
+                    continue;
+                };
+                if !supports_trailers {
+                    warn!(
+                        "Dropped the request trailers of request ID {}, the client does not support them",
+                        request_id
+                    );
+                    continue;
+                }
+                let frame = Frame::Trailers {
+                    id: request_id.clone(),
+                    headers: headers_to_list(&trailers),
+                };
+                if ws_sender.send_frame(frame).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        for piece in chunk.chunks(MAX_CHUNK_SIZE) {
+            let frame = Frame::Data {
+                id: request_id.clone(),
+                chunk: piece.to_vec(),
+            };
+            if ws_sender.send_frame(frame).await.is_err() {
+                return;
             }
         }
-    } else {
-        (StatusCode::NOT_FOUND, "Client not connected").into_response()
     }
+
+    let _ = ws_sender.send_frame(Frame::End { id: request_id }).await;
+}
+
+/// Buffers the whole body and sends the request as JSON text for clients without binary frames.
+async fn send_buffered_request(
+    ws_sender: &FrameSender,
+    head: RequestHead,
+    body: Body,
+) -> Result<(), BoxError> {
+    let body = axum::body::to_bytes(body, BUFFERED_BODY_LIMIT).await?;
+    let tunneled_request = TunneledRequest::from_head(head, &body);
+    ws_sender.send_message(Message::Text(serde_json::to_string(&tunneled_request)?))?;
+    Ok(())
+}
+
+/// Whether buffering a request body failed because it went over `BUFFERED_BODY_LIMIT`.
+fn is_body_too_large(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
 }
 
 #[axum::debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn forward_handler(
     State(app_state): State<Arc<AppState>>,
     ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
-    Path(path): Path<String>,
-    Query(query_params): Query<HashMap<String, String>>,
+    RawQuery(query): RawQuery,
     method: Method,
-    headers: HeaderMap,
-    body: bytes::Bytes,
+    uri: Uri,
+    mut headers: HeaderMap,
+    websocket_upgrade: Option<WebSocketUpgrade>,
+    host_routed: Option<Extension<HostRouted>>,
+    body: Body,
 ) -> Response {
+    // The path is taken from the URI as the visitor sent it, since the `Path` extractor would
+    // percent-decode it and change what the local service sees, e.g. an encoded `/` in an id.
+    let path = uri.path().strip_prefix('/').unwrap_or_default();
     let mut segments = path.splitn(2, '/');
     let client_id = segments.next().unwrap_or_default().to_string();
 
@@ -141,23 +560,55 @@ pub async fn forward_handler(
         Some(p) => format!("/{}", p),
         None => "".to_string(),
     };
+    // On a custom domain the whole host belongs to the tunnel.
+    let cookie_path = match host_routed {
+        Some(_) => "/".to_string(),
+        None => format!("/{}", client_id),
+    };
 
-    let remote_ip = headers
-        .get("X-Forwarded-For")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .and_then(|s| s.trim().parse::<IpAddr>().ok())
-        .unwrap_or(remote_addr.ip());
+    // HTTP/2 visitors send `:authority` instead of `Host`.
+    if !headers.contains_key(HOST) {
+        if let Some(authority) = uri.authority() {
+            if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                headers.insert(HOST, host);
+            }
+        }
+    }
+
+    let remote_ip = visitor_ip(&headers, remote_addr, app_state.trust_forwarded_for);
 
-    handle_forwarding_request(
+    if let Some(websocket_upgrade) = websocket_upgrade {
+        return websocket_tunnel::handle_websocket_upgrade(
+            app_state,
+            client_id,
+            websocket_upgrade,
+            headers,
+            forward_path,
+            query,
+            remote_ip,
+            cookie_path,
+        )
+        .await;
+    }
+
+    // gRPC errors go back in the request's own flavour, e.g. gRPC-Web.
+    let grpc_content_type = grpc::is_grpc_request(&headers)
+        .then(|| headers.get(CONTENT_TYPE).cloned())
+        .flatten();
+    let response = handle_forwarding_request(
         app_state,
         client_id,
         method,
         headers,
         body,
         forward_path,
-        query_params,
+        query,
         remote_ip,
+        cookie_path,
     )
-    .await
+    .await;
+    match grpc_content_type {
+        Some(content_type) => grpc::into_grpc_error(response, content_type).await,
+        None => response,
+    }
 }
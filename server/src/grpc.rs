@@ -0,0 +1,200 @@
+use axum::body::Body;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use std::time::Duration;
+
+/// gRPC status codes the server answers with when a call cannot reach the local service.
+const GRPC_INTERNAL: u16 = 13;
+const GRPC_UNAUTHENTICATED: u16 = 16;
+const GRPC_PERMISSION_DENIED: u16 = 7;
+const GRPC_UNIMPLEMENTED: u16 = 12;
+const GRPC_UNAVAILABLE: u16 = 14;
+const GRPC_DEADLINE_EXCEEDED: u16 = 4;
+const GRPC_UNKNOWN: u16 = 2;
+
+/// Longest error body that is passed on as `grpc-message`.
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// Whether the visitor's request is a gRPC call, including gRPC-Web.
+pub fn is_grpc_request(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// Parses the `grpc-timeout` header, e.g. `500m` or `30S`, into the deadline of the call.
+pub fn grpc_timeout(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("grpc-timeout")?.to_str().ok()?;
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    if amount.is_empty() || amount.len() > 8 {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Turns an HTTP error into a trailers-only gRPC response, since gRPC clients report a bare
+/// HTTP status as an opaque transport error. Responses that already carry a `grpc-status`, as
+/// well as successful ones, are left alone. `content_type` is the one of the request, so that
+/// gRPC-Web clients get an answer they understand.
+pub async fn into_grpc_error(response: Response, content_type: HeaderValue) -> Response {
+    let status = response.status();
+    if status == StatusCode::OK || response.headers().contains_key("grpc-status") {
+        return response;
+    }
+
+    let code = match status.as_u16() {
+        400 => GRPC_INTERNAL,
+        401 => GRPC_UNAUTHENTICATED,
+        403 => GRPC_PERMISSION_DENIED,
+        404 => GRPC_UNIMPLEMENTED,
+        // Both the server and the client answer with 504 when the local service is too slow.
+        504 => GRPC_DEADLINE_EXCEEDED,
+        429 | 502 | 503 => GRPC_UNAVAILABLE,
+        _ => GRPC_UNKNOWN,
+    };
+    let body = axum::body::to_bytes(response.into_body(), MAX_MESSAGE_LEN).await;
+    let message = match body {
+        Ok(body) if !body.is_empty() => String::from_utf8_lossy(&body).into_owned(),
+        _ => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, content_type);
+    headers.insert("grpc-status", HeaderValue::from(code));
+    if let Ok(message) = HeaderValue::from_str(&encode_message(&message)) {
+        headers.insert("grpc-message", message);
+    }
+    response
+}
+
+/// Percent-encodes a `grpc-message` as the gRPC spec requires: every byte outside printable
+/// ASCII, and `%` itself.
+fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeout_of(value: &'static str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert("grpc-timeout", HeaderValue::from_static(value));
+        grpc_timeout(&headers)
+    }
+
+    #[test]
+    fn grpc_timeout_understands_every_unit() {
+        assert_eq!(timeout_of("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(timeout_of("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(timeout_of("30S"), Some(Duration::from_secs(30)));
+        assert_eq!(timeout_of("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(timeout_of("250u"), Some(Duration::from_micros(250)));
+        assert_eq!(
+            timeout_of("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+    }
+
+    #[test]
+    fn grpc_timeout_rejects_malformed_values() {
+        assert_eq!(grpc_timeout(&HeaderMap::new()), None);
+        assert_eq!(timeout_of(""), None);
+        assert_eq!(timeout_of("S"), None);
+        assert_eq!(timeout_of("10"), None);
+        assert_eq!(timeout_of("10s"), None);
+        assert_eq!(timeout_of("-1S"), None);
+        assert_eq!(timeout_of("1.5S"), None);
+        // The spec allows at most eight digits.
+        assert_eq!(timeout_of("123456789S"), None);
+    }
+
+    #[test]
+    fn grpc_requests_are_recognized_by_content_type() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_grpc_request(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(!is_grpc_request(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc+proto"),
+        );
+        assert!(is_grpc_request(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc-web"),
+        );
+        assert!(is_grpc_request(&headers));
+    }
+
+    #[tokio::test]
+    async fn http_errors_become_grpc_statuses() {
+        let response = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("Client 100% offline"))
+            .unwrap();
+        let response =
+            into_grpc_error(response, HeaderValue::from_static("application/grpc")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["grpc-status"], "14");
+        assert_eq!(response.headers()["grpc-message"], "Client 100%25 offline");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/grpc");
+
+        let response = Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .body(Body::empty())
+            .unwrap();
+        let response =
+            into_grpc_error(response, HeaderValue::from_static("application/grpc")).await;
+        assert_eq!(response.headers()["grpc-status"], "4");
+        assert_eq!(response.headers()["grpc-message"], "Gateway Timeout");
+    }
+
+    #[tokio::test]
+    async fn grpc_web_errors_keep_the_request_content_type() {
+        let response = Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::empty())
+            .unwrap();
+        let content_type = HeaderValue::from_static("application/grpc-web-text");
+        let response = into_grpc_error(response, content_type).await;
+        assert_eq!(response.headers()["grpc-status"], "14");
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "application/grpc-web-text"
+        );
+    }
+
+    #[tokio::test]
+    async fn grpc_responses_are_left_alone() {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("grpc-status", "5")
+            .body(Body::empty())
+            .unwrap();
+        let response =
+            into_grpc_error(response, HeaderValue::from_static("application/grpc")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["grpc-status"], "5");
+    }
+}
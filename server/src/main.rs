@@ -1,49 +1,111 @@
 use axum::{
-    extract::ws::Message,
+    middleware,
     routing::{any, get},
     Router,
 };
 use dashmap::DashMap;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::oneshot;
-use tokio::sync::RwLock;
-use tracing::info;
+use tokio::sync::{watch, RwLock};
+use tokio::time::Instant;
+use tracing::{info, warn};
+use yats_protocol as protocol;
 
-use crate::models::TunneledHttpResponse;
+use crate::acme::Acme;
+use crate::forwarding::PendingResponse;
+use crate::tcp_tunnel::TcpConnection;
+use crate::tls::CertResolver;
+use crate::tunnel::Tunnel;
+use crate::udp_tunnel::UdpFlow;
+use crate::websocket::Session;
+use crate::websocket_tunnel::WebSocketStream;
 
 mod access_control;
+mod acme;
 mod asn_updater;
 mod config;
+mod domains;
 mod forwarding;
+mod grpc;
 mod logging;
 mod models;
+mod outbound;
+mod tcp_tunnel;
+mod tls;
+mod tunnel;
+mod udp_tunnel;
 mod websocket;
+mod websocket_tunnel;
+
+/// How long tunnel connections get to close after the visitor side has shut down.
+const TUNNEL_CLOSE_GRACE: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct AppState {
     pub maxmind_license_key: String,
     pub is_production: bool,
     pub secret_token: String,
-    pub active_websockets: Arc<DashMap<String, tokio::sync::mpsc::Sender<Message>>>,
-    pub pending_responses: Arc<DashMap<String, oneshot::Sender<TunneledHttpResponse>>>,
+    pub response_head_timeout: Duration,
+    pub tcp_port_range: Option<RangeInclusive<u16>>,
+    pub udp_port_range: Option<RangeInclusive<u16>>,
+    pub udp_flow_idle_timeout: Duration,
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+    pub drain_timeout: Duration,
+    pub trust_forwarded_for: bool,
+    /// Domain under which each client id gets its own subdomain, e.g. `tunnel.example.com`.
+    pub base_domain: Option<String>,
+    /// Turns `true` once the server starts shutting down.
+    pub shutdown: watch::Receiver<bool>,
+    pub active_websockets: Arc<DashMap<String, Tunnel>>,
+    /// Live sessions by resume token.
+    pub sessions: Arc<DashMap<String, Session>>,
+    pub pending_responses: Arc<DashMap<String, PendingResponse>>,
+    pub websocket_streams: Arc<DashMap<String, WebSocketStream>>,
+    pub tcp_connections: Arc<DashMap<String, TcpConnection>>,
+    pub udp_flows: Arc<DashMap<String, UdpFlow>>,
     pub allowed_paths: Arc<DashMap<String, Vec<String>>>,
     pub allowed_ips: Arc<DashMap<String, Vec<String>>>,
     pub allowed_asns: Arc<DashMap<String, Vec<u32>>>,
+    /// Client id each custom domain is routed to.
+    pub custom_domains: Arc<DashMap<String, String>>,
+    pub acme: Option<Arc<Acme>>,
     pub db_reader: Arc<RwLock<maxminddb::Reader<Vec<u8>>>>,
 }
 
 impl AppState {
-    pub fn new(config: config::Config) -> Self {
+    pub fn new(
+        config: config::Config,
+        acme: Option<Arc<Acme>>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
         Self {
             is_production: config.is_production,
             secret_token: config.secret_token,
+            response_head_timeout: config.response_head_timeout,
+            tcp_port_range: config.tcp_port_range,
+            udp_port_range: config.udp_port_range,
+            udp_flow_idle_timeout: config.udp_flow_idle_timeout,
+            ping_interval: config.ping_interval,
+            pong_timeout: config.pong_timeout,
+            drain_timeout: config.drain_timeout,
+            trust_forwarded_for: config.trust_forwarded_for,
+            base_domain: config.base_domain,
+            shutdown,
             active_websockets: Arc::new(DashMap::new()),
+            sessions: Arc::new(DashMap::new()),
             pending_responses: Arc::new(DashMap::new()),
+            websocket_streams: Arc::new(DashMap::new()),
+            tcp_connections: Arc::new(DashMap::new()),
+            udp_flows: Arc::new(DashMap::new()),
             allowed_paths: Arc::new(DashMap::new()),
             allowed_ips: Arc::new(DashMap::new()),
             allowed_asns: Arc::new(DashMap::new()),
+            custom_domains: Arc::new(DashMap::new()),
+            acme,
             db_reader: Arc::new(RwLock::new(
                 maxminddb::Reader::open_readfile(config.asn_db_path)
                     .expect("Failed to open ASN database"),
@@ -56,7 +118,16 @@ impl AppState {
 #[tokio::main]
 async fn main() {
     let config = config::Config::new();
-    let app_state = Arc::new(AppState::new(config));
+    let tls_config = config.tls.clone();
+    // Shared by the HTTPS listener and ACME, which hands it new certificates right away.
+    let cert_resolver = Arc::new(CertResolver::default());
+    let acme = config
+        .acme
+        .clone()
+        .zip(tls_config.as_ref())
+        .map(|(acme, tls)| Arc::new(Acme::new(acme, tls.cert_dir.clone(), cert_resolver.clone())));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let app_state = Arc::new(AppState::new(config, acme.clone(), shutdown_rx));
     logging::setup_tracing();
 
     let updater_state = app_state.clone();
@@ -67,14 +138,104 @@ async fn main() {
     let app = Router::new()
         .route("/ws", get(websocket::ws_handler))
         .route("/*path", any(forwarding::forward_handler))
-        .with_state(app_state);
+        .with_state(app_state.clone());
+    let app = match &acme {
+        Some(acme) => app.merge(acme::challenge_router(acme.clone())),
+        None => app,
+    };
+    // Custom domains are mapped onto `/<client_id>` paths before the routes above are matched.
+    let app = Router::new()
+        .fallback_service(app)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            domains::route_by_host,
+        ));
+
+    let drain_timeout = app_state.drain_timeout;
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!(
+            "Shutting down. Draining requests in flight for up to {:?}.",
+            drain_timeout
+        );
+        let _ = shutdown_tx.send(true);
+    });
+
+    // With `HTTPS_REDIRECT`, plain HTTP only sends visitors over to the HTTPS listener, apart
+    // from the CA checking HTTP-01 challenges.
+    let plain_app = match &tls_config {
+        Some(tls) if tls.redirect_http => match &acme {
+            Some(acme) => {
+                tls::redirect_router(tls.port).merge(acme::challenge_router(acme.clone()))
+            }
+            None => tls::redirect_router(tls.port),
+        },
+        _ => app.clone(),
+    };
 
     let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
     info!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(
+    let tls_listener = match &tls_config {
+        Some(tls) => Some(TcpListener::bind(("0.0.0.0", tls.port)).await.unwrap()),
+        None => None,
+    };
+    let serve_tls = {
+        let shutdown = app_state.shutdown.clone();
+        async move {
+            if let Some((listener, tls)) = tls_listener.zip(tls_config) {
+                tls::serve(listener, tls, cert_resolver, app, shutdown).await;
+            }
+        }
+    };
+    // Both listeners are up, so the CA can reach the challenges.
+    acme::spawn_renewal_task(app_state.clone());
+    let mut serve_shutdown = app_state.shutdown.clone();
+    let serve = axum::serve(
         listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
+        plain_app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .await
-    .unwrap();
+    .with_graceful_shutdown(async move {
+        let _ = serve_shutdown
+            .wait_for(|shutting_down| *shutting_down)
+            .await;
+    });
+    // Visitors streaming a response would otherwise keep the server up for as long as they like.
+    let mut drain_shutdown = app_state.shutdown.clone();
+    let drain_deadline = async move {
+        let _ = drain_shutdown
+            .wait_for(|shutting_down| *shutting_down)
+            .await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        (result, ()) = async { tokio::join!(serve, serve_tls) } => result.unwrap(),
+        _ = drain_deadline => warn!("Drain deadline passed. Closing the remaining visitor connections."),
+    }
+
+    // Tunnel connections are upgraded and no longer tracked by axum. Each of them closes on its
+    // own once drained, so give them a moment to say goodbye to their clients.
+    let deadline = Instant::now() + TUNNEL_CLOSE_GRACE;
+    while !app_state.active_websockets.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    info!("Server stopped.");
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Ctrl-C received."),
+            _ = sigterm.recv() => info!("SIGTERM received."),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Ctrl-C received.");
+    }
 }
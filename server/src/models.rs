@@ -1,3 +1,7 @@
+use crate::protocol::{BufferedResponse, RequestHead, ResponseHead};
+use crate::tunnel::{Affinity, Balancing};
+use base64::engine::general_purpose;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -13,6 +17,29 @@ pub struct ClientParams {
     pub allowed_ips: Vec<String>,
     #[serde(deserialize_with = "deserialize_u32_vec", default = "default_u32_vec")]
     pub allowed_asns: Vec<u32>,
+    #[serde(default)]
+    pub protocol_version: Option<u16>,
+    #[serde(default)]
+    pub capabilities: Option<String>,
+    #[serde(default)]
+    pub tcp_tunnel: bool,
+    #[serde(default)]
+    pub udp_tunnel: bool,
+    /// Lets several connections share the client id, with requests spread over them.
+    #[serde(default)]
+    pub balancing: Option<Balancing>,
+    /// Share of the requests for `weighted` balancing, relative to the other connections.
+    #[serde(default)]
+    pub weight: Option<u32>,
+    /// Keeps each visitor on one connection of a load balanced tunnel.
+    #[serde(default)]
+    pub affinity: Option<Affinity>,
+    /// Custom domains to route to the tunnel, with certificates from ACME if it is set up.
+    #[serde(
+        deserialize_with = "deserialize_comma_separated_optional",
+        default = "default_vec"
+    )]
+    pub domains: Vec<String>,
 }
 
 fn default_vec() -> Vec<String> {
@@ -55,20 +82,67 @@ where
     Ok(s.split(',').map(|s| s.to_string()).collect())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TunneledRequest {
     pub id: String,
     pub method: String,
     pub path: String,
     pub headers: HashMap<String, String>,
     pub query_params: HashMap<String, String>,
-    pub body: String,
+    pub body: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TunneledHttpResponse {
     pub id: String,
     pub status: u16,
     pub headers: HashMap<String, String>,
     pub body: Option<String>,
 }
+
+impl TunneledRequest {
+    /// Builds the JSON text representation of a request for clients without binary frames.
+    /// Its header and query maps only keep the last value of a repeated field.
+    pub fn from_head(head: RequestHead, body: &[u8]) -> Self {
+        Self {
+            id: head.id,
+            method: head.method,
+            path: head.path,
+            headers: head
+                .headers
+                .into_iter()
+                .map(|(key, value)| (key, value.to_string_lossy()))
+                .collect(),
+            query_params: head
+                .query
+                .map(|query| {
+                    form_urlencoded::parse(query.as_bytes())
+                        .into_owned()
+                        .collect()
+                })
+                .unwrap_or_default(),
+            body: Some(general_purpose::STANDARD.encode(body)),
+        }
+    }
+}
+
+impl TunneledHttpResponse {
+    /// Splits a JSON text response into the same head and raw body a binary frame carries.
+    /// A body that is not valid base64 is treated as empty.
+    pub fn into_head(self) -> BufferedResponse {
+        let body = self
+            .body
+            .and_then(|b| general_purpose::STANDARD.decode(b).ok())
+            .unwrap_or_default();
+        let head = ResponseHead {
+            id: self.id,
+            status: self.status,
+            headers: self
+                .headers
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect(),
+        };
+        (head, body)
+    }
+}
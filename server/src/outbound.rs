@@ -0,0 +1,4 @@
+use axum::extract::ws::Message;
+
+/// The outgoing side of a client's tunnel connection, see `yats_protocol::outbound`.
+pub type FrameSender = yats_protocol::outbound::FrameSender<Message>;
@@ -0,0 +1,136 @@
+use crate::outbound::FrameSender;
+use crate::protocol::{Frame, MAX_CHUNK_SIZE};
+use crate::{access_control, AppState};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// A visitor TCP connection relayed to the client, fed by `Data` frames. The client's send
+/// window bounds how much can be queued, so the sender never blocks.
+pub struct TcpConnection {
+    pub client_id: String,
+    pub sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Binds the first free port of the configured range for a client's TCP tunnel.
+pub async fn bind_tcp_listener(port_range: &RangeInclusive<u16>) -> Option<TcpListener> {
+    for port in port_range.clone() {
+        if let Ok(listener) = TcpListener::bind(("0.0.0.0", port)).await {
+            return Some(listener);
+        }
+    }
+    None
+}
+
+/// Accepts visitor connections on a client's TCP tunnel port until the returned task is aborted.
+pub fn spawn_tcp_listener(
+    app_state: Arc<AppState>,
+    client_id: String,
+    listener: TcpListener,
+    ws_sender: FrameSender,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept TCP connection for '{}': {}", client_id, e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(handle_tcp_connection(
+                app_state.clone(),
+                client_id.clone(),
+                stream,
+                peer_addr,
+                ws_sender.clone(),
+            ));
+        }
+    })
+}
+
+/// Checks a visitor connection against the client's ACLs and relays it over the tunnel.
+async fn handle_tcp_connection(
+    app_state: Arc<AppState>,
+    client_id: String,
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    ws_sender: FrameSender,
+) {
+    let remote_ip = peer_addr.ip();
+    if access_control::is_ip_allowed(&app_state, &client_id, remote_ip).is_err()
+        || access_control::is_asn_allowed(&app_state, &client_id, remote_ip)
+            .await
+            .is_err()
+    {
+        warn!(
+            "Rejected TCP connection from {} for client_id '{}'",
+            peer_addr, client_id
+        );
+        return;
+    }
+
+    let id = Uuid::new_v4().to_string();
+    info!(
+        "Accepted TCP connection {} from {} for client_id '{}'",
+        id, peer_addr, client_id
+    );
+
+    let (data_tx, mut data_rx) = mpsc::unbounded_channel();
+    app_state.tcp_connections.insert(
+        id.clone(),
+        TcpConnection {
+            client_id: client_id.clone(),
+            sender: data_tx,
+        },
+    );
+
+    let open = Frame::TcpOpen {
+        id: id.clone(),
+        peer_addr: peer_addr.to_string(),
+    };
+    if ws_sender.send_frame(open).await.is_err() {
+        app_state.tcp_connections.remove(&id);
+        return;
+    }
+
+    let (mut reader, mut writer) = stream.into_split();
+    let upstream = async {
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let frame = Frame::Data {
+                id: id.clone(),
+                chunk: buf[..n].to_vec(),
+            };
+            if ws_sender.send_frame(frame).await.is_err() {
+                return;
+            }
+        }
+        let _ = ws_sender.send_frame(Frame::End { id: id.clone() }).await;
+    };
+    let downstream = async {
+        while let Some(chunk) = data_rx.recv().await {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+            ws_sender.acknowledge(&id, chunk.len());
+        }
+        let _ = writer.shutdown().await;
+    };
+    tokio::join!(upstream, downstream);
+
+    ws_sender.close_stream(&id);
+    app_state.tcp_connections.remove(&id);
+    info!("TCP connection {} closed", id);
+}
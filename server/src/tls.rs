@@ -0,0 +1,462 @@
+use crate::config::TlsConfig;
+use axum::extract::ConnectInfo;
+use axum::http::header::{HOST, LOCATION, STRICT_TRANSPORT_SECURITY};
+use axum::http::uri::Authority;
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tower_service::Service;
+use tracing::{error, info, warn};
+
+/// How often the certificate directory is checked for new, changed or removed certificates.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a visitor gets to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ALPN protocol of the ACME TLS-ALPN-01 challenge (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Picks the certificate for each TLS handshake by the server name the visitor asked for
+/// (SNI). A certificate stored for `*.example.com` covers every direct subdomain that has no
+/// certificate of its own.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    /// Certificates answering pending TLS-ALPN-01 challenges, only handed to the CA.
+    challenge_certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn insert(&self, host: String, cert: Arc<CertifiedKey>) {
+        self.certs.write().unwrap().insert(host, cert);
+    }
+
+    pub fn remove(&self, host: &str) {
+        self.certs.write().unwrap().remove(host);
+    }
+
+    pub fn insert_challenge(&self, host: String, cert: Arc<CertifiedKey>) {
+        self.challenge_certs.write().unwrap().insert(host, cert);
+    }
+
+    pub fn remove_challenge(&self, host: &str) {
+        self.challenge_certs.write().unwrap().remove(host);
+    }
+
+    /// The certificate for `server_name`, falling back to the wildcard of its parent domain.
+    fn lookup(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+        let certs = self.certs.read().unwrap();
+        let cert = certs.get(&server_name).or_else(|| {
+            let (_, parent) = server_name.split_once('.')?;
+            certs.get(&format!("*.{}", parent))
+        });
+        cert.cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let Some(server_name) = client_hello.server_name() else {
+            warn!("Rejected a TLS handshake without a server name");
+            return None;
+        };
+        let is_acme_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if is_acme_challenge {
+            return self
+                .challenge_certs
+                .read()
+                .unwrap()
+                .get(&server_name.to_ascii_lowercase())
+                .cloned();
+        }
+        let cert = self.lookup(server_name);
+        if cert.is_none() {
+            warn!("No certificate for server name '{}'", server_name);
+        }
+        cert
+    }
+}
+
+/// Loads the certificates in `cert_dir` and keeps them up to date as files change.
+struct CertReloader {
+    cert_dir: PathBuf,
+    resolver: Arc<CertResolver>,
+    /// The newest modification time of each host's files when they were last loaded.
+    loaded: HashMap<String, SystemTime>,
+}
+
+impl CertReloader {
+    /// Brings the resolver in line with the directory. A certificate that fails to load keeps
+    /// its previous version in use, so a renewal that is only half written breaks nothing.
+    fn reload(&mut self) {
+        let entries = match fs::read_dir(&self.cert_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!(
+                    "Failed to read certificate directory {}: {}",
+                    self.cert_dir.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        let mut found = Vec::new();
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            let Some(name) = entry.file_name().to_str().map(str::to_ascii_lowercase) else {
+                continue;
+            };
+            if !dir.is_dir() {
+                continue;
+            }
+            // `*` is awkward in file names, so `_.example.com` holds the wildcard certificate.
+            let host = match name.strip_prefix("_.") {
+                Some(parent) => format!("*.{}", parent),
+                None => name,
+            };
+            let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+            let Some(modified) = newest_modification(&[&cert_path, &key_path]) else {
+                continue;
+            };
+            found.push(host.clone());
+            if self.loaded.get(&host) == Some(&modified) {
+                continue;
+            }
+            self.loaded.insert(host.clone(), modified);
+            match load_certified_key(&cert_path, &key_path) {
+                Ok(cert) => {
+                    info!("Loaded TLS certificate for {}", host);
+                    self.resolver.insert(host, Arc::new(cert));
+                }
+                Err(e) => error!("Failed to load TLS certificate for {}: {}", host, e),
+            }
+        }
+
+        self.loaded.retain(|host, _| {
+            let keep = found.contains(host);
+            if !keep {
+                info!("Removed TLS certificate for {}", host);
+                self.resolver.remove(host);
+            }
+            keep
+        });
+    }
+}
+
+fn newest_modification(paths: &[&Path]) -> Option<SystemTime> {
+    paths
+        .iter()
+        .map(|path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .try_fold(SystemTime::UNIX_EPOCH, |newest, modified| {
+            Some(newest.max(modified?))
+        })
+}
+
+/// Reads a PEM certificate chain and its private key.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", cert_path.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificate found", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("{}: {}", key_path.display(), e))?;
+    certified_key(chain, &key)
+}
+
+/// Pairs a certificate chain with its private key, checking that they belong together.
+pub fn certified_key(
+    chain: Vec<CertificateDer<'static>>,
+    key: &PrivateKeyDer<'_>,
+) -> Result<CertifiedKey, String> {
+    let signing_key = ring::sign::any_supported_type(key).map_err(|e| e.to_string())?;
+    let cert = CertifiedKey::new(chain, signing_key);
+    cert.keys_match().map_err(|e| e.to_string())?;
+    Ok(cert)
+}
+
+/// Serves `app` over HTTPS, and WSS for `/ws`, until the server shuts down. Certificates are
+/// picked by SNI from `TLS_CERT_DIR` and reloaded when their files change. Visitors may
+/// negotiate HTTP/2 through ALPN.
+pub async fn serve(
+    listener: TcpListener,
+    tls: TlsConfig,
+    resolver: Arc<CertResolver>,
+    app: Router,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut reloader = CertReloader {
+        cert_dir: tls.cert_dir.clone(),
+        resolver: resolver.clone(),
+        loaded: HashMap::new(),
+    };
+    reloader.reload();
+    if reloader.loaded.is_empty() {
+        warn!(
+            "No certificates found in {}. Each host needs <host>/cert.pem and <host>/key.pem.",
+            tls.cert_dir.display()
+        );
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            reloader.reload();
+        }
+    });
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("The TLS provider supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols =
+        vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let hsts = tls.hsts_max_age.map(|max_age| {
+        HeaderValue::from_str(&format!("max-age={}", max_age.as_secs()))
+            .expect("max-age is a valid header value")
+    });
+
+    info!("Listening for HTTPS on {}", listener.local_addr().unwrap());
+
+    let graceful = GracefulShutdown::new();
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept an HTTPS connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let hsts = hsts.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        info!("TLS handshake with {} failed: {}", remote_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        info!("TLS handshake with {} timed out", remote_addr);
+                        return;
+                    }
+                };
+            // The CA only checks the certificate and hangs up.
+            if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) {
+                return;
+            }
+
+            let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(remote_addr));
+                // Tells the forwarding code, and through it the local service, that the
+                // visitor came in over HTTPS.
+                request
+                    .headers_mut()
+                    .insert("x-forwarded-proto", HeaderValue::from_static("https"));
+                let response = app.clone().call(request);
+                let hsts = hsts.clone();
+                async move {
+                    let Ok(mut response) = response.await;
+                    if let Some(hsts) = hsts {
+                        response
+                            .headers_mut()
+                            .insert(STRICT_TRANSPORT_SECURITY, hsts);
+                    }
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            if let Err(e) = watcher.watch(connection.into_owned()).await {
+                info!("HTTPS connection from {} ended: {}", remote_addr, e);
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+/// Answers every plain HTTP request with a permanent redirect to the same URL over HTTPS.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(move |uri: Uri, headers: HeaderMap| async move {
+        redirect_to_https(&uri, &headers, https_port)
+    })
+}
+
+fn redirect_to_https(uri: &Uri, headers: &HeaderMap, https_port: u16) -> Response {
+    let host = headers
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| uri.authority().map(|authority| authority.as_str()));
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let Ok(authority) = host.parse::<Authority>() else {
+        return (StatusCode::BAD_REQUEST, "Invalid Host header").into_response();
+    };
+    let host = authority.host();
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = match https_port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+    match HeaderValue::from_str(&location) {
+        Ok(location) => (StatusCode::PERMANENT_REDIRECT, [(LOCATION, location)]).into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST, "Invalid Host header").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTDATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata");
+
+    fn test_cert() -> Arc<CertifiedKey> {
+        let testdata = Path::new(TESTDATA);
+        let cert = load_certified_key(&testdata.join("cert.pem"), &testdata.join("key.pem"));
+        Arc::new(cert.unwrap())
+    }
+
+    /// A fresh certificate directory, removed again when the test is done.
+    struct CertDir(PathBuf);
+
+    impl CertDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("yats-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            CertDir(path)
+        }
+
+        fn add(&self, dir_name: &str) {
+            let dir = self.0.join(dir_name);
+            fs::create_dir_all(&dir).unwrap();
+            for file in ["cert.pem", "key.pem"] {
+                fs::copy(Path::new(TESTDATA).join(file), dir.join(file)).unwrap();
+            }
+        }
+    }
+
+    impl Drop for CertDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn reloader(cert_dir: &CertDir) -> CertReloader {
+        CertReloader {
+            cert_dir: cert_dir.0.clone(),
+            resolver: Arc::new(CertResolver::default()),
+            loaded: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn wildcards_cover_direct_subdomains_without_their_own_certificate() {
+        let resolver = CertResolver::default();
+        let (exact, wildcard) = (test_cert(), test_cert());
+        resolver.insert("api.example.com".to_string(), exact.clone());
+        resolver.insert("*.example.com".to_string(), wildcard.clone());
+
+        assert!(Arc::ptr_eq(
+            &resolver.lookup("api.example.com").unwrap(),
+            &exact
+        ));
+        assert!(Arc::ptr_eq(
+            &resolver.lookup("API.Example.com").unwrap(),
+            &exact
+        ));
+        assert!(Arc::ptr_eq(
+            &resolver.lookup("web.example.com").unwrap(),
+            &wildcard
+        ));
+        assert!(resolver.lookup("a.web.example.com").is_none());
+        assert!(resolver.lookup("example.com").is_none());
+        assert!(resolver.lookup("example.org").is_none());
+    }
+
+    #[test]
+    fn reloader_follows_the_certificate_directory() {
+        let cert_dir = CertDir::new("reload");
+        cert_dir.add("tunnel.example.com");
+        cert_dir.add("_.example.com");
+        // Only directories with both files count.
+        fs::create_dir_all(cert_dir.0.join("incomplete.example.com")).unwrap();
+        let mut reloader = reloader(&cert_dir);
+
+        reloader.reload();
+        let resolver = reloader.resolver.clone();
+        assert_eq!(reloader.loaded.len(), 2);
+        assert!(resolver.lookup("tunnel.example.com").is_some());
+        assert!(resolver.lookup("other.example.com").is_some());
+
+        fs::remove_dir_all(cert_dir.0.join("_.example.com")).unwrap();
+        reloader.reload();
+        assert!(resolver.lookup("tunnel.example.com").is_some());
+        assert!(resolver.lookup("other.example.com").is_none());
+    }
+
+    #[test]
+    fn a_broken_renewal_keeps_the_previous_certificate() {
+        let cert_dir = CertDir::new("renewal");
+        cert_dir.add("tunnel.example.com");
+        let mut reloader = reloader(&cert_dir);
+        reloader.reload();
+        let previous = reloader.resolver.lookup("tunnel.example.com").unwrap();
+
+        let cert_path = cert_dir.0.join("tunnel.example.com/cert.pem");
+        fs::write(&cert_path, "not a certificate").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&cert_path)
+            .and_then(|file| file.set_modified(later))
+            .unwrap();
+        reloader.reload();
+
+        let current = reloader.resolver.lookup("tunnel.example.com").unwrap();
+        assert!(Arc::ptr_eq(&current, &previous));
+    }
+}
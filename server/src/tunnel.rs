@@ -0,0 +1,471 @@
+use crate::outbound::FrameSender;
+use crate::protocol::{Capabilities, Capability};
+use axum::body::Body;
+use axum::http::HeaderName;
+use http_body_util::BodyExt;
+use serde::Deserialize;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Failed requests in a row after which a member is taken out of rotation.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// How long a failing member stays out of rotation before it gets another chance.
+const EJECTION_TIME: Duration = Duration::from_secs(30);
+
+/// How requests are spread over the connections of a load balanced tunnel.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balancing {
+    RoundRobin,
+    LeastInFlight,
+    Weighted,
+}
+
+impl fmt::Display for Balancing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Balancing::RoundRobin => "round_robin",
+            Balancing::LeastInFlight => "least_in_flight",
+            Balancing::Weighted => "weighted",
+        })
+    }
+}
+
+/// What keeps a visitor on the same connection of a load balanced tunnel.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Affinity {
+    /// A cookie the server sets on the first response, naming the connection.
+    Cookie,
+    /// The value of a request header, e.g. a session id set by the local service.
+    Header(String),
+    /// The visitor's IP address.
+    Ip,
+}
+
+impl TryFrom<String> for Affinity {
+    type Error = String;
+
+    /// Parses `cookie`, `ip` or `header:<name>`.
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.split_once(':') {
+            None if value == "cookie" => Ok(Affinity::Cookie),
+            None if value == "ip" => Ok(Affinity::Ip),
+            Some(("header", name)) if HeaderName::from_bytes(name.as_bytes()).is_ok() => {
+                Ok(Affinity::Header(name.to_ascii_lowercase()))
+            }
+            _ => Err(format!("unknown affinity '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for Affinity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Affinity::Cookie => f.write_str("cookie"),
+            Affinity::Header(name) => write!(f, "header:{}", name),
+            Affinity::Ip => f.write_str("ip"),
+        }
+    }
+}
+
+/// The client connections serving one client id. Without balancing there is exactly one.
+pub struct Tunnel {
+    pub balancing: Option<Balancing>,
+    pub affinity: Option<Affinity>,
+    slots: Vec<Slot>,
+    next: usize,
+}
+
+/// A member together with the bookkeeping of the smooth weighted round-robin.
+struct Slot {
+    member: Member,
+    weight: i64,
+    current_weight: i64,
+}
+
+/// One client connection of a tunnel. Cheap to clone; clones share the health counters.
+#[derive(Clone)]
+pub struct Member {
+    pub connection_id: String,
+    pub sender: FrameSender,
+    pub capabilities: Capabilities,
+    health: Arc<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    in_flight: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+/// Counts a request as in flight on a member until it is dropped.
+pub struct InFlight(Arc<Health>);
+
+impl InFlight {
+    /// Keeps the request counted until the visitor has received the whole response body.
+    pub fn hold_for(self, body: Body) -> Body {
+        Body::new(body.map_frame(move |frame| {
+            let _in_flight = &self;
+            frame
+        }))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Member {
+    pub fn new(connection_id: String, sender: FrameSender, capabilities: Capabilities) -> Self {
+        Self {
+            connection_id,
+            sender,
+            capabilities,
+            health: Arc::default(),
+        }
+    }
+
+    pub fn start_request(&self) -> InFlight {
+        self.health.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.health.clone())
+    }
+
+    pub fn record_success(&self) {
+        self.health.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Counts a request the member did not answer properly, and takes the member out of
+    /// rotation for a while once that keeps happening.
+    pub fn record_failure(&self) {
+        let failures = self
+            .health
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if failures >= MAX_CONSECUTIVE_FAILURES {
+            warn!(
+                "Connection {} failed {} requests in a row. Taking it out of rotation for {:?}.",
+                self.connection_id, failures, EJECTION_TIME
+            );
+            self.health.consecutive_failures.store(0, Ordering::Relaxed);
+            *self.health.ejected_until.lock().unwrap() = Some(Instant::now() + EJECTION_TIME);
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.health
+            .ejected_until
+            .lock()
+            .unwrap()
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn supports(&self, required: Option<Capability>) -> bool {
+        required.is_none_or(|capability| self.capabilities.contains(capability))
+    }
+
+    fn in_flight(&self) -> usize {
+        self.health.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+impl Slot {
+    /// The member's rendezvous score for `key`, scaled so that a member with twice the
+    /// weight wins for twice as many keys.
+    fn score(&self, key: &str) -> f64 {
+        let mut hasher = DefaultHasher::new();
+        (key, &self.member.connection_id).hash(&mut hasher);
+        let unit = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+        self.weight as f64 / -unit.ln()
+    }
+}
+
+impl Tunnel {
+    pub fn new(balancing: Option<Balancing>, affinity: Option<Affinity>) -> Self {
+        Self {
+            balancing,
+            affinity,
+            slots: Vec::new(),
+            next: 0,
+        }
+    }
+
+    pub fn add(&mut self, member: Member, weight: u32) {
+        self.slots.push(Slot {
+            member,
+            weight: weight.max(1) as i64,
+            current_weight: 0,
+        });
+    }
+
+    pub fn remove(&mut self, connection_id: &str) {
+        self.slots
+            .retain(|slot| slot.member.connection_id != connection_id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Picks the member for the next request among those that support `required`. Members
+    /// taken out of rotation are skipped unless no other member is left, so a tunnel never
+    /// goes offline because of them.
+    pub fn pick(&mut self, required: Option<Capability>) -> Option<Member> {
+        let candidates = self.candidates(required);
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self.balancing {
+            None | Some(Balancing::RoundRobin) => self.next_in_turn(&candidates),
+            Some(Balancing::LeastInFlight) => {
+                // Starting at the next member in turn spreads ties evenly.
+                let start = self.next_in_turn(&candidates);
+                let offset = candidates.iter().position(|&i| i == start).unwrap_or(0);
+                candidates
+                    .iter()
+                    .cycle()
+                    .skip(offset)
+                    .take(candidates.len())
+                    .copied()
+                    .min_by_key(|&i| self.slots[i].member.in_flight())
+                    .unwrap_or(start)
+            }
+            Some(Balancing::Weighted) => self.next_weighted(&candidates),
+        };
+        Some(self.slots[index].member.clone())
+    }
+
+    /// Picks the member for a visitor identified by `key` under the tunnel's affinity: the
+    /// connection id from the affinity cookie, or the header value or IP address. The same key
+    /// keeps landing on the same member while it is around; otherwise the visitor falls back
+    /// to the balancing strategy, or for header and IP affinity to the member that gets the
+    /// highest score for the key among the remaining ones. Only members that support
+    /// `required` are considered.
+    pub fn pick_for(&mut self, key: Option<&str>, required: Option<Capability>) -> Option<Member> {
+        let Some(key) = key else {
+            return self.pick(required);
+        };
+        match self.affinity {
+            None => self.pick(required),
+            Some(Affinity::Cookie) => {
+                let pinned = self.slots.iter().find(|slot| {
+                    slot.member.connection_id == key
+                        && slot.member.is_healthy()
+                        && slot.member.supports(required)
+                });
+                match pinned {
+                    Some(slot) => Some(slot.member.clone()),
+                    None => self.pick(required),
+                }
+            }
+            Some(Affinity::Header(_) | Affinity::Ip) => {
+                // Rendezvous hashing: only the visitors of a member that leaves are moved.
+                self.candidates(required)
+                    .into_iter()
+                    .map(|i| &self.slots[i])
+                    .max_by(|a, b| a.score(key).total_cmp(&b.score(key)))
+                    .map(|slot| slot.member.clone())
+            }
+        }
+    }
+
+    /// Members that may take requests: the healthy ones that support `required`, or all of
+    /// those if none is healthy.
+    fn candidates(&self, required: Option<Capability>) -> Vec<usize> {
+        let capable: Vec<usize> = (0..self.slots.len())
+            .filter(|&i| self.slots[i].member.supports(required))
+            .collect();
+        let healthy: Vec<usize> = capable
+            .iter()
+            .copied()
+            .filter(|&i| self.slots[i].member.is_healthy())
+            .collect();
+        if healthy.is_empty() {
+            capable
+        } else {
+            healthy
+        }
+    }
+
+    fn next_in_turn(&mut self, candidates: &[usize]) -> usize {
+        let index = candidates[self.next % candidates.len()];
+        self.next = self.next.wrapping_add(1);
+        index
+    }
+
+    /// Smooth weighted round-robin: a member with weight 3 next to one with weight 1 gets
+    /// three of every four requests, interleaved rather than in bursts.
+    fn next_weighted(&mut self, candidates: &[usize]) -> usize {
+        let mut total = 0;
+        let mut best = candidates[0];
+        for &i in candidates {
+            let slot = &mut self.slots[i];
+            slot.current_weight += slot.weight;
+            total += slot.weight;
+            let current_weight = slot.current_weight;
+            if current_weight > self.slots[best].current_weight {
+                best = i;
+            }
+        }
+        self.slots[best].current_weight -= total;
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(connection_id: &str, capabilities: &str) -> Member {
+        Member::new(
+            connection_id.to_string(),
+            FrameSender::default(),
+            Capabilities::parse(capabilities),
+        )
+    }
+
+    fn tunnel(
+        balancing: Option<Balancing>,
+        affinity: Option<Affinity>,
+        members: &[&str],
+    ) -> Tunnel {
+        let mut tunnel = Tunnel::new(balancing, affinity);
+        for connection_id in members {
+            tunnel.add(member(connection_id, "websocket"), 1);
+        }
+        tunnel
+    }
+
+    fn picks(tunnel: &mut Tunnel, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| tunnel.pick(None).unwrap().connection_id)
+            .collect()
+    }
+
+    fn eject(member: &Member) {
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            member.record_failure();
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut tunnel = tunnel(Some(Balancing::RoundRobin), None, &["a", "b", "c"]);
+        assert_eq!(picks(&mut tunnel, 6), ["a", "b", "c", "a", "b", "c"]);
+        assert!(Tunnel::new(None, None).pick(None).is_none());
+    }
+
+    #[test]
+    fn weighted_interleaves_by_weight() {
+        let mut tunnel = Tunnel::new(Some(Balancing::Weighted), None);
+        tunnel.add(member("heavy", ""), 3);
+        tunnel.add(member("light", ""), 1);
+        assert_eq!(
+            picks(&mut tunnel, 8),
+            ["heavy", "heavy", "light", "heavy", "heavy", "heavy", "light", "heavy"]
+        );
+    }
+
+    #[test]
+    fn least_in_flight_prefers_idle_members() {
+        let mut tunnel = tunnel(Some(Balancing::LeastInFlight), None, &["a", "b"]);
+        let busy = tunnel.pick(None).unwrap();
+        let request = busy.start_request();
+        for _ in 0..4 {
+            assert_ne!(tunnel.pick(None).unwrap().connection_id, busy.connection_id);
+        }
+        drop(request);
+        let mut seen = picks(&mut tunnel, 2);
+        seen.sort();
+        assert_eq!(seen, ["a", "b"]);
+    }
+
+    #[test]
+    fn failing_members_are_skipped_until_none_is_left() {
+        let mut tunnel = tunnel(Some(Balancing::RoundRobin), None, &["a", "b"]);
+        let a = tunnel.pick(None).unwrap();
+        a.record_failure();
+        a.record_success();
+        a.record_failure();
+        assert_eq!(picks(&mut tunnel, 2), ["b", "a"]);
+
+        eject(&a);
+        assert_eq!(picks(&mut tunnel, 3), ["b", "b", "b"]);
+
+        let b = tunnel.pick(None).unwrap();
+        eject(&b);
+        let mut seen = picks(&mut tunnel, 2);
+        seen.sort();
+        assert_eq!(seen, ["a", "b"]);
+    }
+
+    #[test]
+    fn required_capabilities_limit_the_candidates() {
+        let mut tunnel = Tunnel::new(Some(Balancing::RoundRobin), Some(Affinity::Cookie));
+        tunnel.add(member("plain", ""), 1);
+        tunnel.add(member("ws", "websocket"), 1);
+        for _ in 0..3 {
+            let picked = tunnel.pick(Some(Capability::WebSocket)).unwrap();
+            assert_eq!(picked.connection_id, "ws");
+        }
+        let pinned = tunnel.pick_for(Some("plain"), Some(Capability::WebSocket));
+        assert_eq!(pinned.unwrap().connection_id, "ws");
+
+        tunnel.remove("ws");
+        assert!(tunnel.pick(Some(Capability::WebSocket)).is_none());
+        assert_eq!(tunnel.pick(None).unwrap().connection_id, "plain");
+    }
+
+    #[test]
+    fn cookie_affinity_pins_healthy_members() {
+        let mut tunnel = tunnel(None, Some(Affinity::Cookie), &["a", "b", "c"]);
+        for _ in 0..3 {
+            assert_eq!(tunnel.pick_for(Some("b"), None).unwrap().connection_id, "b");
+        }
+        // Unknown or ejected connections fall back to the balancing strategy.
+        assert_eq!(
+            tunnel.pick_for(Some("gone"), None).unwrap().connection_id,
+            "a"
+        );
+        eject(&tunnel.pick_for(Some("b"), None).unwrap());
+        assert_ne!(tunnel.pick_for(Some("b"), None).unwrap().connection_id, "b");
+    }
+
+    #[test]
+    fn hashed_affinity_moves_only_the_visitors_of_a_removed_member() {
+        let mut tunnel = tunnel(None, Some(Affinity::Ip), &["a", "b", "c"]);
+        let keys: Vec<String> = (0..64).map(|i| format!("10.0.0.{}", i)).collect();
+        let before: Vec<String> = keys
+            .iter()
+            .map(|key| tunnel.pick_for(Some(key), None).unwrap().connection_id)
+            .collect();
+        for connection_id in ["a", "b", "c"] {
+            assert!(before.iter().any(|picked| picked == connection_id));
+        }
+
+        tunnel.remove("b");
+        for (key, picked) in keys.iter().zip(&before) {
+            let now = tunnel.pick_for(Some(key), None).unwrap().connection_id;
+            if picked == "b" {
+                assert_ne!(now, "b");
+            } else {
+                assert_eq!(&now, picked);
+            }
+        }
+    }
+}
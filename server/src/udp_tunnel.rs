@@ -0,0 +1,168 @@
+use crate::outbound::FrameSender;
+use crate::protocol::{Frame, MAX_CHUNK_SIZE};
+use crate::{access_control, AppState};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// How often idle flows are looked for.
+const FLOW_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A visitor address talking to a client's UDP tunnel port.
+pub struct UdpFlow {
+    pub client_id: String,
+    pub peer_addr: SocketAddr,
+    pub socket: Arc<UdpSocket>,
+    pub last_activity: Instant,
+}
+
+/// Binds the first free port of the configured range for a client's UDP tunnel.
+pub async fn bind_udp_socket(port_range: &RangeInclusive<u16>) -> Option<UdpSocket> {
+    for port in port_range.clone() {
+        if let Ok(socket) = UdpSocket::bind(("0.0.0.0", port)).await {
+            return Some(socket);
+        }
+    }
+    None
+}
+
+/// Sends a reply from the client back to the visitor address of its flow.
+pub async fn send_reply(app_state: &Arc<AppState>, flow_id: &str, payload: &[u8]) {
+    let target = app_state.udp_flows.get_mut(flow_id).map(|mut flow| {
+        flow.last_activity = Instant::now();
+        (flow.socket.clone(), flow.peer_addr)
+    });
+    if let Some((socket, peer_addr)) = target {
+        if let Err(e) = socket.send_to(payload, peer_addr).await {
+            warn!("Failed to send UDP reply to {}: {}", peer_addr, e);
+        }
+    }
+}
+
+/// Relays datagrams arriving on a client's UDP tunnel port until the returned task is aborted.
+/// Each new source address is checked against the client's ACLs and becomes its own flow,
+/// which is closed again after `idle_timeout` without traffic in either direction.
+pub fn spawn_udp_relay(
+    app_state: Arc<AppState>,
+    client_id: String,
+    socket: UdpSocket,
+    ws_sender: FrameSender,
+    idle_timeout: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let socket = Arc::new(socket);
+        // Flow id per visitor address, or `None` for addresses the ACLs rejected.
+        let mut flows: HashMap<SocketAddr, (Option<String>, Instant)> = HashMap::new();
+        let mut sweep = tokio::time::interval(FLOW_SWEEP_INTERVAL);
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (n, peer_addr) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            error!("Failed to receive UDP datagram for '{}': {}", client_id, e);
+                            continue;
+                        }
+                    };
+
+                    // Flows closed by the client are reopened on the next datagram.
+                    let flow_id = match flows.get_mut(&peer_addr) {
+                        Some((flow_id, last_seen))
+                            if flow_id
+                                .as_ref()
+                                .is_none_or(|id| app_state.udp_flows.contains_key(id)) =>
+                        {
+                            *last_seen = Instant::now();
+                            flow_id.clone()
+                        }
+                        _ => {
+                            let flow_id = open_flow(&app_state, &client_id, &socket, peer_addr).await;
+                            flows.insert(peer_addr, (flow_id.clone(), Instant::now()));
+                            flow_id
+                        }
+                    };
+                    let Some(flow_id) = flow_id else {
+                        continue;
+                    };
+                    if let Some(mut flow) = app_state.udp_flows.get_mut(&flow_id) {
+                        flow.last_activity = Instant::now();
+                    }
+
+                    let frame = Frame::Datagram {
+                        id: flow_id,
+                        peer_addr: peer_addr.to_string(),
+                        payload: buf[..n].to_vec(),
+                    };
+                    let _ = ws_sender.send_frame(frame).await;
+                }
+                _ = sweep.tick() => {
+                    let mut expired = Vec::new();
+                    flows.retain(|peer_addr, (flow_id, last_seen)| {
+                        let last_activity = flow_id
+                            .as_ref()
+                            .and_then(|id| app_state.udp_flows.get(id).map(|flow| flow.last_activity))
+                            .unwrap_or(*last_seen);
+                        if last_activity.elapsed() < idle_timeout {
+                            return true;
+                        }
+                        if let Some(flow_id) = flow_id.take() {
+                            info!("UDP flow {} from {} timed out", flow_id, peer_addr);
+                            expired.push(flow_id);
+                        }
+                        false
+                    });
+
+                    for flow_id in expired {
+                        app_state.udp_flows.remove(&flow_id);
+                        let _ = ws_sender.send_frame(Frame::End { id: flow_id }).await;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Checks a new visitor address against the client's ACLs and registers its flow.
+async fn open_flow(
+    app_state: &Arc<AppState>,
+    client_id: &str,
+    socket: &Arc<UdpSocket>,
+    peer_addr: SocketAddr,
+) -> Option<String> {
+    let remote_ip = peer_addr.ip();
+    if access_control::is_ip_allowed(app_state, client_id, remote_ip).is_err()
+        || access_control::is_asn_allowed(app_state, client_id, remote_ip)
+            .await
+            .is_err()
+    {
+        warn!(
+            "Rejected UDP flow from {} for client_id '{}'",
+            peer_addr, client_id
+        );
+        return None;
+    }
+
+    let flow_id = Uuid::new_v4().to_string();
+    info!(
+        "Opened UDP flow {} from {} for client_id '{}'",
+        flow_id, peer_addr, client_id
+    );
+    app_state.udp_flows.insert(
+        flow_id.clone(),
+        UdpFlow {
+            client_id: client_id.to_string(),
+            peer_addr,
+            socket: socket.clone(),
+            last_activity: Instant::now(),
+        },
+    );
+    Some(flow_id)
+}
@@ -1,82 +1,728 @@
 use crate::models::ClientParams;
 use crate::models::TunneledHttpResponse;
-use crate::AppState;
+use crate::outbound::FrameSender;
+use crate::protocol::{
+    self, Capabilities, Capability, CompressionStats, Frame, GoAway, HeaderBytes, ResponseHead,
+    TunnelError,
+};
+use crate::tunnel::{Affinity, Member, Tunnel};
+use crate::{domains, forwarding, tcp_tunnel, udp_tunnel, AppState};
 
 use crate::access_control;
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
         Query, State,
     },
     response::IntoResponse,
 };
 use axum_extra::{headers::Authorization, TypedHeader};
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use futures_util::Stream;
+use http_body::Frame as BodyFrame;
+use http_body_util::StreamBody;
+use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
-use tracing::{error, info};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// How often the traffic counters of a connection are logged while it is busy.
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often a draining connection checks whether its requests have finished.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handshake response header carrying the server's protocol version.
+const PROTOCOL_VERSION_HEADER: &str = "x-yats-protocol-version";
+
+/// Handshake response header carrying the capabilities both sides support.
+const CAPABILITIES_HEADER: &str = "x-yats-capabilities";
+
+/// Handshake response header that tells the client which public port its TCP tunnel got.
+const TCP_PORT_HEADER: &str = "x-yats-tcp-port";
+
+/// Handshake response header that tells the client which public port its UDP tunnel got.
+const UDP_PORT_HEADER: &str = "x-yats-udp-port";
+
+/// Handshake response header with the host name the tunnel is reached at under
+/// `TUNNEL_BASE_DOMAIN`.
+const TUNNEL_HOST_HEADER: &str = "x-yats-tunnel-host";
+
+/// Handshake header carrying the resume token, handed out by the server and sent back by a
+/// reconnecting client to take over its previous session.
+const RESUME_TOKEN_HEADER: &str = "x-yats-resume-token";
+
+/// Close code for a connection that lost the race for its client ID to another one, the
+/// WebSocket counterpart of `409 Conflict`.
+const CLOSE_CODE_CONFLICT: u16 = 4409;
+
+/// A client connection's session, kept so that the client can take it over from a new
+/// connection while the old one has not been noticed as dead yet.
+pub struct Session {
+    pub client_id: String,
+    /// Ends the session. The sender passed along is dropped once the session is cleaned up.
+    takeover: oneshot::Sender<oneshot::Sender<()>>,
+}
 
 #[axum::debug_handler]
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    Query(params): Query<ClientParams>,
+    Query(mut params): Query<ClientParams>,
     auth_header: Option<TypedHeader<Authorization<axum_extra::headers::authorization::Bearer>>>,
+    headers: HeaderMap,
     State(app_state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
     info!("Attempting to upgrade connection to WebSocket on /ws");
 
-    if let Err(e) = access_control::authenticate_client(auth_header, &params, &app_state) {
+    if *app_state.shutdown.borrow() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down").into_response();
+    }
+
+    if let Err(e) = access_control::authenticate_client(auth_header, &app_state) {
         error!("Authentication failed");
         return e.into_response();
     }
 
-    let client_id = params.client_id.clone();
-    let allowed_paths = params.allowed_paths.clone();
-    if let Err(e) = access_control::add_allowed_paths(&app_state, &client_id, allowed_paths) {
-        error!("Failed to add allowed paths");
-        return e.into_response();
+    params.domains = match domains::parse_custom_domains(&params.domains) {
+        Ok(domains) => domains,
+        Err(e) => return e,
+    };
+
+    let resume_token = headers
+        .get(RESUME_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    // A resuming client still holds its ID until its old session is taken over below.
+    let resuming =
+        resume_token.is_some_and(|token| owns_session(&app_state, &params.client_id, token));
+    if !resuming {
+        if let Err(e) = access_control::ensure_client_id_free(&params, &app_state) {
+            return e.into_response();
+        }
+    }
+
+    if let Err(e) = domains::ensure_domains_free(&app_state, &params.client_id, &params.domains) {
+        return e;
     }
 
-    let allowed_ips = params.allowed_ips.clone();
-    if let Err(e) = access_control::add_allowed_ips(&app_state, &client_id, allowed_ips) {
-        error!("Failed to add allowed IPs");
+    if let Err(e) = access_control::ensure_paths_provided(&params) {
         return e.into_response();
     }
 
-    let allowed_asns = params.allowed_asns.clone();
-    if let Err(e) = access_control::add_allowed_asns(&app_state, &client_id, allowed_asns) {
-        error!("Failed to add allowed ASNs");
+    if params.balancing.is_some() && (params.tcp_tunnel || params.udp_tunnel) {
+        error!(
+            "Client '{}' asked for load balancing together with a TCP or UDP tunnel",
+            params.client_id
+        );
+        return (
+            StatusCode::BAD_REQUEST,
+            "TCP and UDP tunnels cannot be load balanced",
+        )
+            .into_response();
+    }
+
+    if params.affinity.is_some() && params.balancing.is_none() {
+        error!(
+            "Client '{}' asked for affinity without load balancing",
+            params.client_id
+        );
+        return (StatusCode::BAD_REQUEST, "Affinity needs load balancing").into_response();
+    }
+
+    let client_id = params.client_id.clone();
+    let capabilities = match negotiate_capabilities(&app_state, &params) {
+        Ok(capabilities) => capabilities,
+        Err(e) => return e.into_response(),
+    };
+    info!(
+        "Negotiated capabilities for client_id '{}': {}",
+        client_id, capabilities
+    );
+
+    let tcp_listener = if params.tcp_tunnel {
+        match allocate_tcp_listener(&app_state, &params, &capabilities).await {
+            Ok(listener) => Some(listener),
+            Err(e) => return e.into_response(),
+        }
+    } else {
+        None
+    };
+    let udp_socket = if params.udp_tunnel {
+        match allocate_udp_socket(&app_state, &params, &capabilities).await {
+            Ok(socket) => Some(socket),
+            Err(e) => return e.into_response(),
+        }
+    } else {
+        None
+    };
+
+    // Every check has passed, so the old session can go. It is cleaned up before anything is
+    // registered for the new one.
+    if let Some(resume_token) = resume_token {
+        take_over_session(&app_state, &client_id, resume_token).await;
+    }
+    if let Err(e) = access_control::ensure_client_id_free(&params, &app_state) {
         return e.into_response();
     }
 
-    ws.on_upgrade(move |socket| handle_websocket(socket, app_state, client_id))
+    let tcp_port = tcp_listener
+        .as_ref()
+        .and_then(|listener| listener.local_addr().ok())
+        .map(|addr| addr.port());
+    let udp_port = udp_socket
+        .as_ref()
+        .and_then(|socket| socket.local_addr().ok())
+        .map(|addr| addr.port());
+    let tunnel_host = domains::tunnel_host(&app_state, &client_id)
+        .and_then(|host| HeaderValue::from_str(&host).ok());
+
+    let capabilities_header = HeaderValue::from_str(&capabilities.to_string());
+    let member = Member::new(
+        Uuid::new_v4().to_string(),
+        FrameSender::default(),
+        capabilities,
+    );
+    let resume_token = Uuid::new_v4().to_string();
+    let resume_token_header = HeaderValue::from_str(&resume_token);
+
+    let mut response = ws.on_upgrade(move |socket| {
+        handle_websocket(
+            socket,
+            app_state,
+            params,
+            member,
+            resume_token,
+            tcp_listener,
+            udp_socket,
+        )
+    });
+    response.headers_mut().insert(
+        PROTOCOL_VERSION_HEADER,
+        HeaderValue::from(protocol::PROTOCOL_VERSION),
+    );
+    if let Ok(value) = resume_token_header {
+        response.headers_mut().insert(RESUME_TOKEN_HEADER, value);
+    }
+    if let Ok(value) = capabilities_header {
+        response.headers_mut().insert(CAPABILITIES_HEADER, value);
+    }
+    if let Some(port) = tcp_port {
+        response
+            .headers_mut()
+            .insert(TCP_PORT_HEADER, HeaderValue::from(port));
+    }
+    if let Some(port) = udp_port {
+        response
+            .headers_mut()
+            .insert(UDP_PORT_HEADER, HeaderValue::from(port));
+    }
+    if let Some(host) = tunnel_host {
+        response.headers_mut().insert(TUNNEL_HOST_HEADER, host);
+    }
+    response
+}
+
+/// Whether `resume_token` belongs to the client's current session.
+fn owns_session(app_state: &AppState, client_id: &str, resume_token: &str) -> bool {
+    app_state
+        .sessions
+        .get(resume_token)
+        .is_some_and(|session| session.client_id == client_id)
 }
 
-async fn handle_websocket(mut socket: WebSocket, app_state: Arc<AppState>, client_id: String) {
+/// Ends the client's current session if `resume_token` belongs to it, and waits until it is
+/// cleaned up, so the new connection starts from a clean slate. Requests still in flight on the
+/// old session fail with a 502. Without a matching token the old session is left alone.
+async fn take_over_session(app_state: &Arc<AppState>, client_id: &str, resume_token: &str) {
+    // Removing the entry claims the takeover, so two connections cannot both win it.
+    let Some((_, session)) = app_state
+        .sessions
+        .remove_if(resume_token, |_, session| session.client_id == client_id)
+    else {
+        return;
+    };
+
+    info!(
+        "Client '{}' is resuming its session from a new connection. Closing the old one.",
+        client_id
+    );
+    let (done_tx, done_rx) = oneshot::channel();
+    if session.takeover.send(done_tx).is_err() {
+        // The old session is already on its way out.
+        return;
+    }
+    // If it takes longer, the client is told the ID is still in use and tries again later.
+    if tokio::time::timeout(app_state.pong_timeout, done_rx)
+        .await
+        .is_err()
+    {
+        warn!(
+            "The old session of client '{}' did not close within {:?}",
+            client_id, app_state.pong_timeout
+        );
+    }
+}
+
+/// Checks the client's protocol version and works out which optional features both sides
+/// support. Clients from before negotiation send no version and get the JSON text protocol.
+fn negotiate_capabilities(
+    app_state: &AppState,
+    params: &ClientParams,
+) -> Result<Capabilities, impl IntoResponse> {
+    let client_capabilities = match params.protocol_version {
+        Some(version)
+            if (protocol::MIN_PROTOCOL_VERSION..=protocol::PROTOCOL_VERSION).contains(&version) =>
+        {
+            Capabilities::parse(params.capabilities.as_deref().unwrap_or_default())
+        }
+        Some(version) => {
+            warn!(
+                "Rejecting client '{}' with unsupported protocol version {}",
+                params.client_id, version
+            );
+            let message = format!(
+                "Protocol version {} is not supported. This server speaks versions {} to {}; please upgrade the {}.",
+                version,
+                protocol::MIN_PROTOCOL_VERSION,
+                protocol::PROTOCOL_VERSION,
+                if version < protocol::MIN_PROTOCOL_VERSION { "client" } else { "server" },
+            );
+            return Err((StatusCode::UPGRADE_REQUIRED, message));
+        }
+        None => {
+            info!(
+                "Client '{}' predates protocol negotiation. Using the JSON text protocol.",
+                params.client_id
+            );
+            Capabilities::default()
+        }
+    };
+
+    Ok(client_capabilities.intersection(&server_capabilities(app_state)))
+}
+
+/// Optional features this server supports with its current configuration.
+fn server_capabilities(app_state: &AppState) -> Capabilities {
+    [
+        Capability::BinaryFrames,
+        Capability::Streaming,
+        Capability::Compression,
+        Capability::WebSocket,
+        Capability::Cancel,
+        Capability::Errors,
+        Capability::GoAway,
+        Capability::Trailers,
+    ]
+    .into_iter()
+    .chain(
+        app_state
+            .tcp_port_range
+            .is_some()
+            .then_some(Capability::Tcp),
+    )
+    .chain(
+        app_state
+            .udp_port_range
+            .is_some()
+            .then_some(Capability::Udp),
+    )
+    .collect()
+}
+
+/// Binds a public port for a client that asked for a raw TCP tunnel.
+async fn allocate_tcp_listener(
+    app_state: &Arc<AppState>,
+    params: &ClientParams,
+    capabilities: &Capabilities,
+) -> Result<TcpListener, impl IntoResponse> {
+    let Some(port_range) = &app_state.tcp_port_range else {
+        error!(
+            "Client '{}' requested a TCP tunnel, but TCP tunnels are disabled",
+            params.client_id
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "TCP tunnels are not enabled on this server",
+        ));
+    };
+
+    if !capabilities.contains(Capability::Tcp) {
+        error!(
+            "Client '{}' requested a TCP tunnel without the tcp capability",
+            params.client_id
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "TCP tunnels require binary frames and the tcp capability",
+        ));
+    }
+
+    match tcp_tunnel::bind_tcp_listener(port_range).await {
+        Some(listener) => Ok(listener),
+        None => {
+            error!(
+                "No free TCP tunnel port for client '{}' in range {:?}",
+                params.client_id, port_range
+            );
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No free TCP tunnel port available",
+            ))
+        }
+    }
+}
+
+/// Binds a public port for a client that asked for a UDP tunnel.
+async fn allocate_udp_socket(
+    app_state: &Arc<AppState>,
+    params: &ClientParams,
+    capabilities: &Capabilities,
+) -> Result<UdpSocket, impl IntoResponse> {
+    let Some(port_range) = &app_state.udp_port_range else {
+        error!(
+            "Client '{}' requested a UDP tunnel, but UDP tunnels are disabled",
+            params.client_id
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "UDP tunnels are not enabled on this server",
+        ));
+    };
+
+    if !capabilities.contains(Capability::Udp) {
+        error!(
+            "Client '{}' requested a UDP tunnel without the udp capability",
+            params.client_id
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "UDP tunnels require binary frames and the udp capability",
+        ));
+    }
+
+    match udp_tunnel::bind_udp_socket(port_range).await {
+        Some(socket) => Ok(socket),
+        None => {
+            error!(
+                "No free UDP tunnel port for client '{}' in range {:?}",
+                params.client_id, port_range
+            );
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No free UDP tunnel port available",
+            ))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_websocket(
+    mut socket: WebSocket,
+    app_state: Arc<AppState>,
+    params: ClientParams,
+    member: Member,
+    resume_token: String,
+    tcp_listener: Option<TcpListener>,
+    udp_socket: Option<UdpSocket>,
+) {
+    let client_id = params.client_id.clone();
     info!("WebSocket connected for client_id: {}", client_id);
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(100);
-    app_state.active_websockets.insert(client_id.clone(), tx);
+    let tx = member.sender.clone();
+    let connection_id = member.connection_id.clone();
+    let capabilities = member.capabilities.clone();
+    let balancing = params.balancing;
+    // Checked again under the tunnel's entry, since another connection for the same client id
+    // may have been accepted between the handshake and now.
+    let joined = match app_state.active_websockets.entry(client_id.clone()) {
+        Entry::Occupied(mut tunnel) => {
+            match access_control::join_conflict(&params, &app_state, tunnel.get()) {
+                Some(reason) => Err(reason),
+                None => {
+                    tunnel.get_mut().add(member, params.weight.unwrap_or(1));
+                    Ok(tunnel.get().len())
+                }
+            }
+        }
+        Entry::Vacant(entry) => {
+            domains::claim_custom_domains(&app_state, &client_id, &params.domains).map(|()| {
+                access_control::add_allow_lists(&app_state, &params);
+                let mut tunnel = Tunnel::new(balancing, params.affinity.clone());
+                tunnel.add(member, params.weight.unwrap_or(1));
+                entry.insert(tunnel);
+                1
+            })
+        }
+    };
+    let members = match joined {
+        Ok(members) => members,
+        Err(reason) => {
+            let close_frame = CloseFrame {
+                code: CLOSE_CODE_CONFLICT,
+                reason: reason.into(),
+            };
+            let _ = socket.send(Message::Close(Some(close_frame))).await;
+            return;
+        }
+    };
+    // Ordered only once the connection is in, so a rejected one costs the CA nothing.
+    if let Some(acme) = &app_state.acme {
+        for domain in &params.domains {
+            acme.request_certificate(domain.clone());
+        }
+    }
+    if let Some(balancing) = balancing {
+        info!(
+            "Client_id '{}' now has {} connection(s), balanced {} with affinity {}",
+            client_id,
+            members,
+            balancing,
+            params
+                .affinity
+                .as_ref()
+                .map_or("none".to_string(), Affinity::to_string)
+        );
+    }
+    let (takeover_tx, mut takeover_rx) = oneshot::channel();
+    app_state.sessions.insert(
+        resume_token.clone(),
+        Session {
+            client_id: client_id.clone(),
+            takeover: takeover_tx,
+        },
+    );
+    // Held until the cleanup below is done, which tells the connection taking over to go ahead.
+    let mut takeover_done = None;
+    let tcp_listener_task = tcp_listener.map(|listener| {
+        info!(
+            "TCP tunnel for client_id '{}' listening on {:?}",
+            client_id,
+            listener.local_addr()
+        );
+        tcp_tunnel::spawn_tcp_listener(app_state.clone(), client_id.clone(), listener, tx.clone())
+    });
+    let udp_relay_task = udp_socket.map(|udp_socket| {
+        info!(
+            "UDP tunnel for client_id '{}' listening on {:?}",
+            client_id,
+            udp_socket.local_addr()
+        );
+        udp_tunnel::spawn_udp_relay(
+            app_state.clone(),
+            client_id.clone(),
+            udp_socket,
+            tx.clone(),
+            app_state.udp_flow_idle_timeout,
+        )
+    });
+    // Response bodies still being streamed to visitors, keyed by request id. The client's send
+    // window bounds how much each of them buffers.
+    let mut response_bodies: HashMap<String, ResponseBodySender> = HashMap::new();
+    let compress = capabilities.contains(Capability::Compression);
+    let supports_cancel = capabilities.contains(Capability::Cancel);
+    let mut stats = CompressionStats::default();
+    let mut logged_stats = stats;
+    let mut stats_interval = tokio::time::interval(STATS_LOG_INTERVAL);
+    let mut heartbeat = tokio::time::interval_at(
+        Instant::now() + app_state.ping_interval,
+        app_state.ping_interval,
+    );
+    // Set while a ping is unanswered. Any message from the client counts as an answer.
+    let mut pong_deadline: Option<Instant> = None;
+    let supports_go_away = capabilities.contains(Capability::GoAway);
+    let mut shutdown = app_state.shutdown.clone();
+    // Set once the server is shutting down. Until then, requests in flight may finish.
+    let mut drain_deadline: Option<Instant> = None;
+    let mut drain_check = tokio::time::interval(DRAIN_CHECK_INTERVAL);
 
     loop {
         tokio::select! {
-            Some(msg) = rx.recv() => {
-                if socket.send(msg).await.is_err() {
-                    error!("Failed to send message to websocket");
+            // An error means the server is exiting, which is no different.
+            _ = shutdown.changed(), if drain_deadline.is_none() => {
+                drain_deadline = Some(Instant::now() + app_state.drain_timeout);
+                if supports_go_away {
+                    info!("Sending GoAway to client_id '{}'", client_id);
+                    let go_away = GoAway {
+                        message: "The server is shutting down. Please reconnect.".to_string(),
+                        drain_timeout_secs: app_state.drain_timeout.as_secs(),
+                    };
+                    let _ = tx.send_frame(Frame::GoAway(go_away)).await;
+                }
+            }
+            _ = drain_check.tick(), if drain_deadline.is_some() => {
+                let in_flight = response_bodies.len()
+                    + app_state
+                        .pending_responses
+                        .iter()
+                        .filter(|pending| pending.connection_id == connection_id)
+                        .count();
+                if in_flight == 0 {
+                    info!("All requests of client_id '{}' have finished.", client_id);
                     break;
                 }
             }
-            Some(Ok(msg)) = socket.recv() => {
+            _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(Instant::now)), if drain_deadline.is_some() => {
+                warn!(
+                    "Drain deadline passed with requests of client_id '{}' still in flight.",
+                    client_id
+                );
+                break;
+            }
+            done = &mut takeover_rx => {
+                takeover_done = done.ok();
+                info!("Closing the old connection of client_id '{}', which was taken over.", client_id);
+                break;
+            }
+            msg = tx.next() => {
+                let msg = match msg {
+                    Message::Binary(frame) => {
+                        let uncompressed_len = frame.len();
+                        let frame = if compress { protocol::compress_frame(frame) } else { frame };
+                        stats.record(uncompressed_len, frame.len());
+                        Message::Binary(frame)
+                    }
+                    other => other,
+                };
+                // A half-open connection can stall writes as well as reads.
+                match tokio::time::timeout(app_state.pong_timeout, socket.send(msg)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => {
+                        error!("Failed to send message to websocket");
+                        break;
+                    }
+                    Err(_) => {
+                        warn!(
+                            "Sending to client_id '{}' stalled for {:?}. Closing the tunnel.",
+                            client_id, app_state.pong_timeout
+                        );
+                        break;
+                    }
+                }
+            }
+            _ = heartbeat.tick() => {
+                if pong_deadline.is_none() {
+                    pong_deadline = Some(Instant::now() + app_state.pong_timeout);
+                    let _ = tx.send_message(Message::Ping(Vec::new()));
+                }
+            }
+            _ = tokio::time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                warn!(
+                    "No answer from client_id '{}' within {:?} of a ping. Closing the tunnel.",
+                    client_id, app_state.pong_timeout
+                );
+                break;
+            }
+            _ = stats_interval.tick() => {
+                if stats != logged_stats {
+                    info!("Frames sent to client_id '{}' so far: {}", client_id, stats);
+                    logged_stats = stats;
+                }
+            }
+            msg = socket.recv() => {
+                let Some(Ok(msg)) = msg else {
+                    break;
+                };
+                pong_deadline = None;
                 match msg {
                     Message::Text(text) => {
                         info!("Received text from WebSocket: {}", text);
                         if let Ok(response) = serde_json::from_str::<TunneledHttpResponse>(&text) {
-                            if let Some((_, tx)) = app_state.pending_responses.remove(&response.id) {
-                                if tx.send(response).is_err() {
-                                    error!("Failed to send response to pending request");
-                                }
-                            }
+                            let (head, body) = response.into_head();
+                            complete_pending_response(&app_state, head, Body::from(body));
+                        } else if let Ok(error) = serde_json::from_str::<TunnelError>(&text) {
+                            handle_client_error(&app_state, &mut response_bodies, error);
                         }
                     }
                     Message::Binary(bin) => {
-                        info!("Received binary from WebSocket: {:?}" , bin);
+                        match Frame::decode(&bin) {
+                            Ok(Frame::Response(head)) if head.status == 101 => {
+                                info!("Client opened tunnelled WebSocket for request ID: {}", head.id);
+                                complete_pending_response(&app_state, head, Body::empty());
+                            }
+                            Ok(Frame::Response(head)) => {
+                                info!("Received binary response frame for request ID: {}", head.id);
+                                let (body_tx, body_rx) = mpsc::unbounded_channel();
+                                let request_id = head.id.clone();
+                                let body = ResponseBody {
+                                    body_rx,
+                                    tx: tx.clone(),
+                                    request_id: request_id.clone(),
+                                    supports_cancel,
+                                    complete: false,
+                                };
+                                let body = Body::new(StreamBody::new(body.into_stream()));
+                                if complete_pending_response(&app_state, head, body) {
+                                    response_bodies.insert(request_id, body_tx);
+                                }
+                            }
+                            Ok(Frame::Data { id, chunk }) => {
+                                // Chunks nobody reads anymore still hand back their credit.
+                                let len = chunk.len();
+                                if let Some(body_tx) = response_bodies.get(&id) {
+                                    if body_tx.send(Ok(BodyFrame::data(chunk.into()))).is_err() {
+                                        response_bodies.remove(&id);
+                                        tx.acknowledge(&id, len);
+                                    }
+                                } else {
+                                    let sender = app_state
+                                        .tcp_connections
+                                        .get(&id)
+                                        .map(|connection| connection.sender.clone());
+                                    if sender.is_none_or(|sender| sender.send(chunk).is_err()) {
+                                        app_state.tcp_connections.remove(&id);
+                                        tx.acknowledge(&id, len);
+                                    }
+                                }
+                            }
+                            Ok(Frame::WindowUpdate { id, increment }) => {
+                                tx.grant(&id, increment);
+                            }
+                            Ok(Frame::Trailers { id, headers }) => {
+                                if let Some(body_tx) = response_bodies.get(&id) {
+                                    let mut trailers = HeaderMap::new();
+                                    forwarding::append_headers(&mut trailers, headers, &id);
+                                    let _ = body_tx.send(Ok(BodyFrame::trailers(trailers)));
+                                }
+                            }
+                            Ok(Frame::End { id }) => {
+                                response_bodies.remove(&id);
+                                app_state.websocket_streams.remove(&id);
+                                app_state.tcp_connections.remove(&id);
+                                app_state.udp_flows.remove(&id);
+                            }
+                            Ok(Frame::Datagram { id, payload, .. }) => {
+                                udp_tunnel::send_reply(&app_state, &id, &payload).await;
+                            }
+                            Ok(Frame::WebSocketMessage { id, kind, payload }) => {
+                                let sender = app_state
+                                    .websocket_streams
+                                    .get(&id)
+                                    .map(|stream| stream.sender.clone());
+                                let len = payload.len();
+                                if sender.is_none_or(|sender| sender.send((kind, payload)).is_err()) {
+                                    app_state.websocket_streams.remove(&id);
+                                    tx.acknowledge(&id, len);
+                                }
+                            }
+                            Ok(Frame::Error(error)) => {
+                                handle_client_error(&app_state, &mut response_bodies, error);
+                            }
+                            Ok(frame) => {
+                                warn!("Received unexpected frame from client: {:?}", frame);
+                            }
+                            Err(e) => {
+                                error!("Failed to decode binary frame from WebSocket: {}", e);
+                                if let Some(id) = protocol::frame_id(&bin) {
+                                    fail_request(&app_state, &mut response_bodies, &id);
+                                }
+                            }
+                        }
                     }
                     Message::Ping(ping) => {
                         info!("Received Ping from WebSocket. Sending Pong.");
@@ -86,7 +732,7 @@ async fn handle_websocket(mut socket: WebSocket, app_state: Arc<AppState>, clien
                         }
                     }
                     Message::Pong(_) => {
-                        info!("Received Pong from WebSocket.");
+                        debug!("Received Pong from WebSocket.");
                     }
                     Message::Close(close_frame) => {
                         info!("Received Close from WebSocket: {:?}", close_frame);
@@ -94,14 +740,187 @@ async fn handle_websocket(mut socket: WebSocket, app_state: Arc<AppState>, clien
                     }
                 }
             }
-            else => {
-                break;
-            }
         }
     }
 
+    if drain_deadline.is_some() {
+        let close_frame = CloseFrame {
+            code: close_code::AWAY,
+            reason: "server is shutting down".into(),
+        };
+        let _ = tokio::time::timeout(
+            app_state.pong_timeout,
+            socket.send(Message::Close(Some(close_frame))),
+        )
+        .await;
+    }
+
     info!("WebSocket for client_id: {} disconnected.", client_id);
-    app_state.active_websockets.remove(&client_id);
-    app_state.allowed_paths.remove(&client_id);
-    app_state.allowed_ips.remove(&client_id);
+    info!(
+        "Frames sent to client_id '{}' in total: {}",
+        client_id, stats
+    );
+    tx.close();
+    // The other members of a load balanced tunnel keep serving it. The last one takes the allow
+    // lists along, under the tunnel's entry so a new connection cannot register its own first.
+    if let Entry::Occupied(mut tunnel) = app_state.active_websockets.entry(client_id.clone()) {
+        tunnel.get_mut().remove(&connection_id);
+        if tunnel.get().is_empty() {
+            tunnel.remove();
+            access_control::remove_allow_lists(&app_state, &client_id);
+            domains::release_custom_domains(&app_state, &client_id);
+        }
+    }
+    app_state.sessions.remove(&resume_token);
+    // Release every visitor still waiting on this connection instead of letting them time out.
+    app_state
+        .pending_responses
+        .retain(|_, pending| pending.connection_id != connection_id);
+    for (_, body_tx) in response_bodies.drain() {
+        let _ = body_tx.send(Err(io::Error::other("the tunnel connection was lost")));
+    }
+    app_state
+        .websocket_streams
+        .retain(|_, stream| stream.connection_id != connection_id);
+    if let Some(task) = tcp_listener_task {
+        task.abort();
+    }
+    app_state
+        .tcp_connections
+        .retain(|_, connection| connection.client_id != client_id);
+    if let Some(task) = udp_relay_task {
+        task.abort();
+    }
+    app_state
+        .udp_flows
+        .retain(|_, flow| flow.client_id != client_id);
+    drop(takeover_done);
+}
+
+/// Hands a response to the visitor waiting for it. Returns `false` if nobody is waiting anymore.
+fn complete_pending_response(app_state: &Arc<AppState>, head: ResponseHead, body: Body) -> bool {
+    let Some((_, pending)) = app_state.pending_responses.remove(&head.id) else {
+        return false;
+    };
+    if pending.sender.send((head, body)).is_err() {
+        error!("Failed to send response to pending request");
+        return false;
+    }
+    true
+}
+
+/// Logs an error the client reported and fails the request it belongs to, if any.
+fn handle_client_error(
+    app_state: &Arc<AppState>,
+    response_bodies: &mut HashMap<String, ResponseBodySender>,
+    error: TunnelError,
+) {
+    match &error.id {
+        Some(id) => {
+            warn!(
+                "Client could not handle request ID {} ({:?}): {}",
+                id, error.code, error.message
+            );
+            fail_request(app_state, response_bodies, id);
+        }
+        None => warn!(
+            "Client could not handle a message ({:?}): {}",
+            error.code, error.message
+        ),
+    }
+}
+
+/// Answers the visitor waiting for request `id` with a 502 right away instead of letting it
+/// time out. A response body that is already streaming is broken off, so the visitor does not
+/// mistake it for a complete one.
+fn fail_request(
+    app_state: &Arc<AppState>,
+    response_bodies: &mut HashMap<String, ResponseBodySender>,
+    id: &str,
+) {
+    if let Some(body_tx) = response_bodies.remove(id) {
+        let _ = body_tx.send(Err(io::Error::other("the client failed the response")));
+        return;
+    }
+    let head = ResponseHead {
+        id: id.to_string(),
+        status: StatusCode::BAD_GATEWAY.as_u16(),
+        headers: vec![(
+            "content-type".to_string(),
+            HeaderBytes::from("text/plain; charset=utf-8".to_string()),
+        )],
+    };
+    complete_pending_response(app_state, head, Body::from("Bad Gateway"));
+}
+
+/// Feeds chunks and trailers of a response body, or the error that broke it off, to a
+/// `ResponseBody`.
+type ResponseBodySender = mpsc::UnboundedSender<io::Result<BodyFrame<Bytes>>>;
+
+/// A response body on its way to the visitor. The client gets more credit as the visitor takes
+/// each chunk. If the visitor goes away before the `End` frame, the request is cancelled on the
+/// client, which is likely waiting for credit and would not notice otherwise. Chunks that were
+/// buffered but never read still hand back their credit.
+struct ResponseBody {
+    body_rx: mpsc::UnboundedReceiver<io::Result<BodyFrame<Bytes>>>,
+    tx: FrameSender,
+    request_id: String,
+    supports_cancel: bool,
+    complete: bool,
+}
+
+impl ResponseBody {
+    /// Turns the body into a stream for `StreamBody`.
+    fn into_stream(self) -> impl Stream<Item = io::Result<BodyFrame<Bytes>>> {
+        futures_util::stream::unfold(self, |mut body| async move {
+            match body.body_rx.recv().await {
+                Some(Ok(frame)) => {
+                    if let Some(chunk) = frame.data_ref() {
+                        body.tx.acknowledge(&body.request_id, chunk.len());
+                    }
+                    Some((Ok(frame), body))
+                }
+                // The request has already failed, so there is nothing left to cancel.
+                Some(Err(e)) => {
+                    body.complete = true;
+                    Some((Err(e), body))
+                }
+                None => {
+                    body.complete = true;
+                    drop(body);
+                    None
+                }
+            }
+        })
+    }
+}
+
+impl Drop for ResponseBody {
+    fn drop(&mut self) {
+        if self.complete {
+            return;
+        }
+        info!(
+            "Visitor stopped reading the response for request ID: {}",
+            self.request_id
+        );
+        // Chunks arriving from now on are acknowledged by the message loop.
+        self.body_rx.close();
+        while let Ok(Ok(frame)) = self.body_rx.try_recv() {
+            if let Some(chunk) = frame.data_ref() {
+                self.tx.acknowledge(&self.request_id, chunk.len());
+            }
+        }
+        if !self.supports_cancel {
+            return;
+        }
+
+        info!("Cancelling request ID {} on the client", self.request_id);
+        let frame = Frame::Cancel {
+            id: self.request_id.clone(),
+        };
+        if let Ok(frame) = frame.encode() {
+            let _ = self.tx.send_message(Message::Binary(frame));
+        }
+    }
 }
@@ -0,0 +1,262 @@
+use crate::forwarding::{
+    append_headers, build_response, check_access, headers_to_list, is_gateway_error,
+    request_authority, request_scheme, route_request, strip_affinity_cookie, PendingRequest, Route,
+};
+use crate::outbound::FrameSender;
+use crate::protocol::{Capability, Frame, RequestHead, WebSocketMessageKind};
+use crate::AppState;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::http::header::SET_COOKIE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Headers of the local handshake response that axum sets on its own for the visitor.
+const HANDSHAKE_HEADERS: [&str; 5] = [
+    "connection",
+    "upgrade",
+    "sec-websocket-accept",
+    "sec-websocket-extensions",
+    "sec-websocket-protocol",
+];
+
+/// A visitor WebSocket relayed to the client, fed by `WebSocketMessage` frames. The client's
+/// send window bounds how much can be queued, so the sender never blocks.
+pub struct WebSocketStream {
+    pub connection_id: String,
+    pub sender: mpsc::UnboundedSender<(WebSocketMessageKind, Vec<u8>)>,
+}
+
+/// Asks the client to open a WebSocket to its local service and, once that handshake has
+/// succeeded, accepts the visitor's upgrade and relays messages in both directions.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_websocket_upgrade(
+    app_state: Arc<AppState>,
+    client_id: String,
+    websocket_upgrade: WebSocketUpgrade,
+    mut headers: HeaderMap,
+    forward_path: String,
+    query: Option<String>,
+    remote_ip: IpAddr,
+    cookie_path: String,
+) -> Response {
+    info!(
+        "Tunnelling WebSocket upgrade for client_id: {}, path: {}",
+        client_id, forward_path
+    );
+
+    if let Err(response) = check_access(&app_state, &client_id, &forward_path, remote_ip).await {
+        return response;
+    }
+
+    let Some(Route {
+        member,
+        affinity_cookie,
+    }) = route_request(
+        &app_state,
+        &client_id,
+        &headers,
+        remote_ip,
+        &cookie_path,
+        Some(Capability::WebSocket),
+    )
+    else {
+        if !app_state.active_websockets.contains_key(&client_id) {
+            return (StatusCode::NOT_FOUND, "Client not connected").into_response();
+        }
+        // Connected, but none of the client's connections can tunnel WebSockets.
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            "Client does not support WebSocket tunnelling",
+        )
+            .into_response();
+    };
+    strip_affinity_cookie(&mut headers);
+    let ws_sender = member.sender.clone();
+    let in_flight = member.start_request();
+
+    let request_id = Uuid::new_v4().to_string();
+    let head = RequestHead {
+        id: request_id.clone(),
+        method: "GET".to_string(),
+        path: forward_path,
+        headers: headers_to_list(&headers),
+        query,
+        has_body: false,
+        authority: request_authority(&headers),
+        scheme: Some(request_scheme(&headers)),
+    };
+
+    let (message_tx, message_rx) = mpsc::unbounded_channel();
+    app_state.websocket_streams.insert(
+        request_id.clone(),
+        WebSocketStream {
+            connection_id: member.connection_id.clone(),
+            sender: message_tx,
+        },
+    );
+    let supports_cancel = member.capabilities.contains(Capability::Cancel);
+    // Dropped together with this future if the visitor disconnects while we wait.
+    let (pending, rx) = PendingRequest::new(
+        &app_state,
+        &member.connection_id,
+        &ws_sender,
+        &request_id,
+        supports_cancel,
+    );
+
+    if let Err(e) = ws_sender.send_frame(Frame::WebSocketOpen(head)).await {
+        error!("Failed to forward WebSocket upgrade to websocket: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to forward request to client",
+        )
+            .into_response();
+    }
+
+    match tokio::time::timeout(app_state.response_head_timeout, rx).await {
+        Ok(Ok((response_head, _))) if response_head.status == 101 => {
+            pending.complete();
+            member.record_success();
+            let selected_protocol = response_head
+                .headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case("sec-websocket-protocol"))
+                .and_then(|(_, value)| String::from_utf8(value.0.clone()).ok());
+            let websocket_upgrade = match selected_protocol {
+                Some(protocol) => websocket_upgrade.protocols([protocol]),
+                None => websocket_upgrade,
+            };
+
+            let relay_state = app_state.clone();
+            let relay_id = request_id.clone();
+            let mut response = websocket_upgrade.on_upgrade(move |socket| async move {
+                // The WebSocket counts as in flight on its tunnel connection until it closes.
+                let _in_flight = in_flight;
+                relay_websocket(socket, relay_state, ws_sender, relay_id, message_rx).await
+            });
+
+            // Pass on what the local service set during the handshake, such as cookies.
+            let headers = response_head
+                .headers
+                .into_iter()
+                .filter(|(key, _)| !HANDSHAKE_HEADERS.contains(&key.to_ascii_lowercase().as_str()))
+                .collect();
+            append_headers(response.headers_mut(), headers, &request_id);
+            if let Some(cookie) = affinity_cookie {
+                response.headers_mut().append(SET_COOKIE, cookie);
+            }
+            response
+        }
+        Ok(Ok((response_head, response_body))) => {
+            pending.complete();
+            if is_gateway_error(response_head.status) {
+                member.record_failure();
+            } else {
+                member.record_success();
+            }
+            info!(
+                "Local service refused WebSocket upgrade for request ID {} with status {}",
+                request_id, response_head.status
+            );
+            app_state.websocket_streams.remove(&request_id);
+            build_response(response_head, response_body)
+        }
+        Ok(Err(_)) => {
+            info!(
+                "Tunnel closed before the WebSocket handshake of request ID {} finished",
+                request_id
+            );
+            member.record_failure();
+            (StatusCode::BAD_GATEWAY, "Client disconnected").into_response()
+        }
+        Err(_) => {
+            info!(
+                "Timed out waiting for the WebSocket handshake of request ID {}",
+                request_id
+            );
+            member.record_failure();
+            (StatusCode::GATEWAY_TIMEOUT, "Request to client timed out").into_response()
+        }
+    }
+}
+
+/// Relays messages between the visitor's WebSocket and the client until either side closes.
+async fn relay_websocket(
+    mut socket: WebSocket,
+    app_state: Arc<AppState>,
+    ws_sender: FrameSender,
+    request_id: String,
+    mut message_rx: mpsc::UnboundedReceiver<(WebSocketMessageKind, Vec<u8>)>,
+) {
+    info!("Tunnelled WebSocket opened for request ID: {}", request_id);
+
+    let mut close_sent = false;
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let (kind, payload) = match message {
+                    Some(Ok(Message::Text(text))) => (WebSocketMessageKind::Text, text.into_bytes()),
+                    Some(Ok(Message::Binary(bin))) => (WebSocketMessageKind::Binary, bin),
+                    Some(Ok(Message::Close(close_frame))) => {
+                        let (code, reason) = close_frame
+                            .map(|f| (Some(f.code), f.reason.into_owned()))
+                            .unwrap_or_default();
+                        (WebSocketMessageKind::Close { code, reason }, Vec::new())
+                    }
+                    Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                    Some(Err(_)) | None => {
+                        if close_sent {
+                            break;
+                        }
+                        let _ = ws_sender.send_frame(Frame::End { id: request_id.clone() }).await;
+                        break;
+                    }
+                };
+
+                // Keep reading after a close so the automatic close reply gets flushed.
+                close_sent |= matches!(kind, WebSocketMessageKind::Close { .. });
+                let frame = Frame::WebSocketMessage { id: request_id.clone(), kind, payload };
+                if ws_sender.send_frame(frame).await.is_err() {
+                    break;
+                }
+            }
+            message = message_rx.recv() => {
+                let len = message.as_ref().map_or(0, |(_, payload)| payload.len());
+                let message = match message {
+                    Some((WebSocketMessageKind::Text, payload)) => {
+                        Message::Text(String::from_utf8_lossy(&payload).into_owned())
+                    }
+                    Some((WebSocketMessageKind::Binary, payload)) => Message::Binary(payload),
+                    Some((WebSocketMessageKind::Close { code, reason }, _)) => {
+                        let _ = socket
+                            .send(Message::Close(code.map(|code| CloseFrame {
+                                code,
+                                reason: reason.into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    None => {
+                        let _ = socket.send(Message::Close(None)).await;
+                        break;
+                    }
+                };
+
+                if socket.send(message).await.is_err() {
+                    let _ = ws_sender.send_frame(Frame::End { id: request_id.clone() }).await;
+                    break;
+                }
+                ws_sender.acknowledge(&request_id, len);
+            }
+        }
+    }
+
+    ws_sender.close_stream(&request_id);
+    app_state.websocket_streams.remove(&request_id);
+    info!("Tunnelled WebSocket closed for request ID: {}", request_id);
+}